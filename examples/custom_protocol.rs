@@ -0,0 +1,87 @@
+//! Protocol defined outside of the simulator crate, run with the same
+//! network model and metrics output as the built-in ones:
+//! `cargo run --release --example custom_protocol`
+
+use basalt_sim::metrics::Metric;
+use basalt_sim::net::{self, App, MessageSize, NetParams, Network, PeerRef, Simulator, HEADER_SIZE};
+use basalt_sim::output::{Format, Output, Sink};
+
+/// Rumor mongering: every node that knows the rumor pushes it to a random
+/// peer at each time unit
+struct Rumor {
+    informed: bool,
+}
+
+#[derive(Clone)]
+struct Push;
+
+impl MessageSize for Push {
+    fn size(&self) -> usize {
+        HEADER_SIZE
+    }
+}
+
+struct Metrics {
+    n_informed: usize,
+}
+
+impl net::Metrics for Metrics {
+    fn empty() -> Self {
+        Metrics{ n_informed: 0 }
+    }
+    fn net_combine(&mut self, other: &Self) {
+        self.n_informed += other.n_informed;
+    }
+    fn headers() -> Vec<&'static str> {
+        vec!["n_informed"]
+    }
+    fn values(&self) -> Vec<Metric> {
+        vec![Metric::int(self.n_informed)]
+    }
+}
+
+const ROUND: usize = 0;
+
+impl App for Rumor {
+    type Init = ();
+    type Msg = Push;
+    type Metrics = Metrics;
+
+    fn new() -> Self {
+        Rumor{ informed: false }
+    }
+
+    fn init(&mut self, my_id: PeerRef, net: &mut dyn Network<Push>, _init: &()) {
+        self.informed = my_id == 0;
+        net.set_timer(1, ROUND);
+    }
+
+    fn handle(&mut self, _net: &mut dyn Network<Push>, _from: PeerRef, _msg: &Push) {
+        self.informed = true;
+    }
+
+    fn on_timer(&mut self, net: &mut dyn Network<Push>, _tag: usize) {
+        if self.informed {
+            for peer in net.sample_peers(1) {
+                net.send(peer, Push);
+            }
+        }
+        net.set_timer(1, ROUND);
+    }
+
+    fn metrics(&mut self, _net: &mut dyn Network<Push>) -> Metrics {
+        Metrics{ n_informed: self.informed as usize }
+    }
+}
+
+fn main() {
+    let params = NetParams{ drop: "0.1".parse().unwrap(), ..NetParams::default() };
+    let mut sim = Simulator::<Rumor>::new(1000, 0, 42, &params, &());
+    let mut out = Output::new(Format::Table, None);
+    out.header(&sim.headers());
+    out.row(&sim.values());
+    for _ in 0..20 {
+        sim.step();
+        out.row(&sim.values());
+    }
+}
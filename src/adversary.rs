@@ -0,0 +1,525 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use rand::RngCore;
+
+use super::net::PeerRef;
+use super::roles::RoleTable;
+use super::util::{hash, sample_nocopy};
+
+/// What Byzantine nodes do at a given time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Behavior {
+    /// Follow the protocol like correct nodes
+    Honest,
+    /// Send nothing, not even replies
+    Silent,
+    /// Answer requests but send nothing unsolicited
+    Lurking,
+    /// Run the attack
+    Malicious,
+}
+
+/// Strategy of the Byzantine nodes, independent of the protocol: the protocol
+/// decides which messages are sent, the strategy decides when to attack,
+/// which nodes to target and which identifiers to advertise.
+pub trait Adversary: Send + Sync {
+    /// Behavior of Byzantine node `me` at time `time`
+    fn behavior(&self, me: PeerRef, time: u64, knowledge: &Knowledge) -> Behavior;
+
+    /// Nodes that Byzantine node `me` sends unsolicited messages to at time
+    /// `time`, at most `n` of them, `online` being the nodes currently online
+    fn targets(&self, me: PeerRef, time: u64, n: usize, online: &[PeerRef], knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef>;
+
+    /// Identifiers advertised by Byzantine nodes to node `to`, at most `n` of them
+    fn advertised(&self, to: PeerRef, n: usize, knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef>;
+
+    /// Correct nodes specifically targeted by the attack
+    fn victims(&self) -> &[PeerRef] {
+        &[]
+    }
+
+    /// Probability that the adversary learns a seed drawn by a correct node
+    fn seed_leak(&self) -> f64 {
+        0.
+    }
+
+    /// Whether the adversary uses the views reported by correct nodes
+    fn reads_metrics(&self) -> bool {
+        false
+    }
+}
+
+/// What the adversary knows: its own identities, and for some adversaries
+/// the seeds of correct nodes, used to attack protocols that keep the peer
+/// minimizing `hash(seed, peer)` in each slot of their view
+#[derive(Clone, Default)]
+pub struct Knowledge {
+    /// Byzantine identities currently online
+    byzantine: Vec<PeerRef>,
+    /// Sybil identities minted by the adversary, candidates for the known
+    /// seeds even while they are offline
+    pool: Vec<PeerRef>,
+    /// Correct nodes
+    correct: Vec<PeerRef>,
+    /// For each node and slot, the known seed and the Byzantine identity
+    /// with the lowest hash for that seed
+    seeds: BTreeMap<PeerRef, BTreeMap<usize, (u64, PeerRef)>>,
+    /// Byzantine identities with the lowest hash for at least one known seed
+    kept: BTreeSet<PeerRef>,
+    /// Correct nodes by increasing fraction of Byzantine nodes in their view,
+    /// as of the metrics of the previous step
+    least_exposed: Vec<PeerRef>,
+}
+
+impl Knowledge {
+    pub fn new(roles: &RoleTable, online: &[PeerRef], pool: Vec<PeerRef>) -> Self {
+        Self{
+            byzantine: online.iter().cloned().filter(|p| roles.is_byzantine(*p)).collect(),
+            pool,
+            correct: roles.correct(),
+            ..Self::default()
+        }
+    }
+
+    /// Updates the Byzantine identities that are online, which changes
+    /// the identities that win the known seeds
+    pub fn set_online(&mut self, roles: &RoleTable, online: &[PeerRef]) {
+        let byzantine = online.iter().cloned().filter(|p| roles.is_byzantine(*p)).collect::<Vec<_>>();
+        if byzantine == self.byzantine {
+            return;
+        }
+        self.byzantine = byzantine;
+        let known = self.seeds.iter()
+            .flat_map(|(node, slots)| slots.iter().map(move |(slot, (seed, _))| (*node, *slot, *seed)))
+            .collect::<Vec<_>>();
+        for (node, slot, seed) in known {
+            self.learn(node, slot, Some(seed));
+        }
+        self.update_kept();
+    }
+
+    /// Records the seed of a slot of a node, `None` meaning that the
+    /// adversary did not learn the seed that replaced the previous one
+    pub fn learn(&mut self, node: PeerRef, slot: usize, seed: Option<u64>) {
+        let candidates = self.byzantine.iter().chain(self.pool.iter());
+        let best = seed.and_then(|seed| {
+            candidates.cloned().min_by_key(|b| hash(seed, *b)).map(|b| (seed, b))
+        });
+        let slots = self.seeds.entry(node).or_default();
+        match best {
+            Some(x) => { slots.insert(slot, x); }
+            None => { slots.remove(&slot); }
+        }
+    }
+
+    pub fn update_kept(&mut self) {
+        self.kept = self.seeds.values()
+            .flat_map(|slots| slots.values().map(|(_, b)| *b))
+            .collect();
+    }
+
+    /// Minted Sybil identities that win at least one known seed but are
+    /// not online yet, and should join the network
+    pub fn to_deploy(&self, online: &[bool]) -> Vec<PeerRef> {
+        self.pool.iter().cloned()
+            .filter(|b| !online[*b] && self.kept.contains(b))
+            .collect()
+    }
+
+    /// Ranks correct nodes from the number of Byzantine nodes in their view
+    /// and the size of their view, as reported by each of them
+    pub fn rank(&mut self, mut reports: Vec<(PeerRef, usize, usize)>) {
+        let fraction = |n_byzantine: usize, view_size: usize| {
+            n_byzantine as f64 / std::cmp::max(1, view_size) as f64
+        };
+        reports.sort_by(|(a, na, va), (b, nb, vb)| {
+            fraction(*na, *va).total_cmp(&fraction(*nb, *vb)).then(a.cmp(b))
+        });
+        self.least_exposed = reports.into_iter().map(|(node, _, _)| node).collect();
+    }
+
+    /// Byzantine identities that win the known slots of `node`
+    fn best_for(&self, node: PeerRef) -> Vec<PeerRef> {
+        let mut ret = self.seeds.get(&node)
+            .map(|slots| slots.values().map(|(_, b)| *b).collect::<Vec<_>>())
+            .unwrap_or_default();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum WhichAdversary {
+    /// `flood`, push Byzantine identifiers to random nodes and answer all requests with them
+    #[default]
+    Flood,
+    /// `balanced`, like flood but pushes are spread evenly over correct nodes
+    Balanced,
+    /// `eclipse:<victims>`, like flood but pushes only go to the victims, given
+    /// as a comma-separated list of nodes or ranges (e.g. `300-309,400`)
+    Eclipse(Vec<PeerRef>),
+    /// `silent`, never send anything
+    Silent,
+    /// `delayed:<time>`, silent until the given time, then flood
+    Delayed(u64),
+    /// `honest-then-malicious:<time>`, follow the protocol until the given time, then flood
+    HonestThenMalicious(u64),
+    /// `lurking:<time>`, answer requests but push nothing until the given time,
+    /// then flood; this is what `-s/--attack-start-time` used to do
+    Lurking(u64),
+    /// `seed-aware:<p>`, like flood but each seed drawn by a correct node is learnt
+    /// with probability p, and the identities that win the known seeds of a node
+    /// are advertised to it
+    SeedAware(f64),
+    /// `sybil:<p>:<budget>`, like seed-aware but the adversary also mints `budget`
+    /// cheap Sybil identities, which join the network only once they win a known
+    /// seed; Byzantine identities that win no known seed stay silent
+    Sybil(f64, usize),
+    /// `adaptive`, omniscient adversary that spreads its pushes over the correct
+    /// nodes that had the fewest Byzantine neighbors at the previous step
+    Adaptive,
+}
+
+impl std::str::FromStr for WhichAdversary {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = s.split(':').collect::<Vec<_>>();
+        let time = || args.get(1).and_then(|x| x.parse::<u64>().ok())
+            .ok_or("invalid attack start time");
+        let prob = || match args.get(1).and_then(|x| x.parse::<f64>().ok()) {
+            Some(p) if (0. ..=1.).contains(&p) => Ok(p),
+            _ => Err("invalid seed leak probability"),
+        };
+        match args[0] {
+            "flood" => Ok(Self::Flood),
+            "balanced" => Ok(Self::Balanced),
+            "eclipse" => {
+                let mut victims = vec![];
+                for item in args.get(1).ok_or("missing eclipse victims")?.split(',') {
+                    let node = |x: &str| x.parse::<PeerRef>().map_err(|_| "invalid eclipse victim");
+                    match item.find('-') {
+                        Some(i) => victims.extend(node(&item[..i])?..=node(&item[i+1..])?),
+                        None => victims.push(node(item)?),
+                    }
+                }
+                if victims.is_empty() {
+                    return Err("empty set of eclipse victims");
+                }
+                victims.sort();
+                victims.dedup();
+                Ok(Self::Eclipse(victims))
+            }
+            "silent" => Ok(Self::Silent),
+            "delayed" => Ok(Self::Delayed(time()?)),
+            "honest-then-malicious" => Ok(Self::HonestThenMalicious(time()?)),
+            "lurking" => Ok(Self::Lurking(time()?)),
+            "seed-aware" => Ok(Self::SeedAware(prob()?)),
+            "sybil" => {
+                let budget = args.get(2).and_then(|x| x.parse::<usize>().ok())
+                    .ok_or("invalid Sybil identity budget")?;
+                Ok(Self::Sybil(prob()?, budget))
+            }
+            "adaptive" => Ok(Self::Adaptive),
+            _ => Err("invalid adversary strategy"),
+        }
+    }
+}
+
+impl WhichAdversary {
+    /// Number of Sybil identities minted by the adversary, on top of the
+    /// Byzantine nodes of the network
+    pub fn sybil_budget(&self) -> usize {
+        match self {
+            Self::Sybil(_, budget) => *budget,
+            _ => 0,
+        }
+    }
+
+    pub fn build(&self, roles: &RoleTable) -> Arc<dyn Adversary> {
+        let flood = Flood;
+        match self {
+            Self::Flood => Arc::new(flood),
+            Self::Balanced => Arc::new(Balanced),
+            Self::Eclipse(victims) => {
+                assert!(victims.iter().all(|v| roles.correct().binary_search(v).is_ok()),
+                        "eclipse victims must be correct nodes");
+                Arc::new(Eclipse{ victims: victims.clone() })
+            }
+            Self::Silent => Arc::new(Phased{ before: Behavior::Silent, start: u64::MAX, then: flood }),
+            Self::Delayed(start) => Arc::new(Phased{ before: Behavior::Silent, start: *start, then: flood }),
+            Self::HonestThenMalicious(start) => Arc::new(Phased{ before: Behavior::Honest, start: *start, then: flood }),
+            Self::Lurking(start) => Arc::new(Phased{ before: Behavior::Lurking, start: *start, then: flood }),
+            Self::SeedAware(leak) => Arc::new(SeedAware{ flood, leak: *leak, sybil: false }),
+            Self::Sybil(leak, _) => Arc::new(SeedAware{ flood, leak: *leak, sybil: true }),
+            Self::Adaptive => Arc::new(Adaptive{ flood }),
+        }
+    }
+}
+
+fn byzantine_ids(knowledge: &Knowledge, n: usize, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+    let mut byzantines = knowledge.byzantine.clone();
+    sample_nocopy(&mut byzantines[..], n, rng)
+}
+
+struct Flood;
+
+impl Adversary for Flood {
+    fn behavior(&self, _me: PeerRef, _time: u64, _knowledge: &Knowledge) -> Behavior {
+        Behavior::Malicious
+    }
+
+    fn targets(&self, me: PeerRef, _time: u64, n: usize, online: &[PeerRef], _knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        let mut candidates = online.iter().cloned().filter(|p| *p != me).collect::<Vec<_>>();
+        sample_nocopy(&mut candidates[..], n, rng)
+    }
+
+    fn advertised(&self, _to: PeerRef, n: usize, knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        byzantine_ids(knowledge, n, rng)
+    }
+}
+
+struct Balanced;
+
+impl Adversary for Balanced {
+    fn behavior(&self, _me: PeerRef, _time: u64, _knowledge: &Knowledge) -> Behavior {
+        Behavior::Malicious
+    }
+
+    fn targets(&self, me: PeerRef, time: u64, n: usize, online: &[PeerRef], knowledge: &Knowledge, _rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        // Byzantine nodes take turns over the correct nodes that are online,
+        // so that each of them receives the same number of pushes (up to one)
+        // at each step
+        let correct = knowledge.correct.iter().cloned()
+            .filter(|p| online.binary_search(p).is_ok())
+            .collect::<Vec<_>>();
+        let rank = knowledge.byzantine.binary_search(&me).unwrap_or_else(|i| i);
+        let first = (time as usize * knowledge.byzantine.len() + rank) * n;
+        (0..std::cmp::min(n, correct.len()))
+            .map(|k| correct[(first + k) % correct.len()])
+            .collect()
+    }
+
+    fn advertised(&self, _to: PeerRef, n: usize, knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        byzantine_ids(knowledge, n, rng)
+    }
+}
+
+struct Eclipse {
+    victims: Vec<PeerRef>,
+}
+
+impl Adversary for Eclipse {
+    fn behavior(&self, _me: PeerRef, _time: u64, _knowledge: &Knowledge) -> Behavior {
+        Behavior::Malicious
+    }
+
+    fn targets(&self, _me: PeerRef, _time: u64, n: usize, online: &[PeerRef], _knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        // The flooding budget is spent on the victims that are online only,
+        // split evenly between them, the remainder going to random victims
+        let mut victims = self.victims.iter().cloned()
+            .filter(|p| online.binary_search(p).is_ok())
+            .collect::<Vec<_>>();
+        if victims.is_empty() {
+            return vec![];
+        }
+        let mut ret = victims.iter()
+            .flat_map(|v| std::iter::repeat_n(*v, n / victims.len()))
+            .collect::<Vec<_>>();
+        let n_rest = n % victims.len();
+        ret.extend(sample_nocopy(&mut victims[..], n_rest, rng));
+        ret
+    }
+
+    fn advertised(&self, _to: PeerRef, n: usize, knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        byzantine_ids(knowledge, n, rng)
+    }
+
+    fn victims(&self) -> &[PeerRef] {
+        &self.victims
+    }
+}
+
+/// Behaves as `before` until time `start`, then as `then`
+struct Phased<A: Adversary> {
+    before: Behavior,
+    start: u64,
+    then: A,
+}
+
+impl<A: Adversary> Adversary for Phased<A> {
+    fn behavior(&self, me: PeerRef, time: u64, knowledge: &Knowledge) -> Behavior {
+        if time < self.start {
+            self.before
+        } else {
+            self.then.behavior(me, time, knowledge)
+        }
+    }
+
+    fn targets(&self, me: PeerRef, time: u64, n: usize, online: &[PeerRef], knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        self.then.targets(me, time, n, online, knowledge, rng)
+    }
+
+    fn advertised(&self, to: PeerRef, n: usize, knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        self.then.advertised(to, n, knowledge, rng)
+    }
+
+    fn victims(&self) -> &[PeerRef] {
+        self.then.victims()
+    }
+
+    fn seed_leak(&self) -> f64 {
+        self.then.seed_leak()
+    }
+}
+
+struct SeedAware {
+    flood: Flood,
+    leak: f64,
+    sybil: bool,
+}
+
+impl Adversary for SeedAware {
+    fn behavior(&self, me: PeerRef, _time: u64, knowledge: &Knowledge) -> Behavior {
+        if self.sybil && !knowledge.kept.contains(&me) {
+            Behavior::Silent
+        } else {
+            Behavior::Malicious
+        }
+    }
+
+    fn targets(&self, me: PeerRef, time: u64, n: usize, online: &[PeerRef], knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        self.flood.targets(me, time, n, online, knowledge, rng)
+    }
+
+    fn advertised(&self, to: PeerRef, n: usize, knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        let mut ret = knowledge.best_for(to);
+        if ret.len() >= n {
+            return sample_nocopy(&mut ret[..], n, rng);
+        }
+        // Complete with other identities, which may win slots whose seed is unknown
+        let mut others = if self.sybil {
+            knowledge.kept.iter().cloned().filter(|b| !ret.contains(b)).collect::<Vec<_>>()
+        } else {
+            knowledge.byzantine.iter().cloned().filter(|b| !ret.contains(b)).collect::<Vec<_>>()
+        };
+        let n_others = n - ret.len();
+        ret.extend(sample_nocopy(&mut others[..], n_others, rng));
+        ret
+    }
+
+    fn seed_leak(&self) -> f64 {
+        self.leak
+    }
+}
+
+struct Adaptive {
+    flood: Flood,
+}
+
+impl Adversary for Adaptive {
+    fn behavior(&self, _me: PeerRef, _time: u64, _knowledge: &Knowledge) -> Behavior {
+        Behavior::Malicious
+    }
+
+    fn targets(&self, me: PeerRef, time: u64, n: usize, online: &[PeerRef], knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        let ranking = &knowledge.least_exposed;
+        if ranking.is_empty() {
+            return self.flood.targets(me, time, n, online, knowledge, rng);
+        }
+        // The pushes of all Byzantine nodes go to distinct nodes, starting
+        // with the least exposed ones
+        let rank = knowledge.byzantine.binary_search(&me).unwrap_or_else(|i| i);
+        (0..std::cmp::min(n, ranking.len()))
+            .map(|k| ranking[(rank * n + k) % ranking.len()])
+            .collect()
+    }
+
+    fn advertised(&self, to: PeerRef, n: usize, knowledge: &Knowledge, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        self.flood.advertised(to, n, knowledge, rng)
+    }
+
+    fn reads_metrics(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::seeded_rng;
+
+    #[test]
+    fn parse_sybil() {
+        let adversary = "sybil:0.5:100".parse::<WhichAdversary>().unwrap();
+        assert_eq!(adversary, WhichAdversary::Sybil(0.5, 100));
+        assert_eq!(adversary.sybil_budget(), 100);
+        assert_eq!("seed-aware:0.5".parse::<WhichAdversary>().unwrap().sybil_budget(), 0);
+        assert!("sybil:0.5".parse::<WhichAdversary>().is_err());
+    }
+
+    #[test]
+    fn lurking_answers_before_start() {
+        let roles = RoleTable::new(10, 2, false, 0);
+        let knowledge = Knowledge::new(&roles, &(0..10).collect::<Vec<_>>(), vec![]);
+        let adversary = "lurking:5".parse::<WhichAdversary>().unwrap();
+        assert_eq!(adversary, WhichAdversary::Lurking(5));
+        let adversary = adversary.build(&roles);
+        assert_eq!(adversary.behavior(0, 4, &knowledge), Behavior::Lurking);
+        assert_eq!(adversary.behavior(0, 5, &knowledge), Behavior::Malicious);
+    }
+
+    #[test]
+    fn eclipse_budget_is_split_between_online_victims() {
+        let roles = RoleTable::new(10, 2, false, 0);
+        let online = [0, 1, 3, 4, 6, 7];
+        let knowledge = Knowledge::new(&roles, &online, vec![]);
+        let adversary = WhichAdversary::Eclipse(vec![3, 4, 5]).build(&roles);
+        let mut rng = seeded_rng(0, 0);
+        for n in 0..10 {
+            let targets = adversary.targets(0, 0, n, &online, &knowledge, &mut rng);
+            assert_eq!(targets.len(), n);
+            let n3 = targets.iter().filter(|p| **p == 3).count();
+            let n4 = targets.iter().filter(|p| **p == 4).count();
+            assert_eq!(n3 + n4, n);
+            assert!(n3.abs_diff(n4) <= 1);
+        }
+        let offline = [0, 1, 6, 7];
+        assert!(adversary.targets(0, 0, 5, &offline, &knowledge, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn minted_identities_win_more_seeds() {
+        let mut roles = RoleTable::new(20, 2, false, 0);
+        let online = (0..20).collect::<Vec<_>>();
+        let mut seed_aware = Knowledge::new(&roles, &online, vec![]);
+        let pool = roles.mint(200);
+        let mut sybil = Knowledge::new(&roles, &online, pool.clone());
+        for slot in 0..50 {
+            let seed = hash(slot as u64, 12345);
+            seed_aware.learn(10, slot, Some(seed));
+            sybil.learn(10, slot, Some(seed));
+        }
+        seed_aware.update_kept();
+        sybil.update_kept();
+
+        // The winner among the minted identities is never worse
+        for (slot, (seed, b)) in sybil.seeds[&10].iter() {
+            let (_, b0) = seed_aware.seeds[&10][slot];
+            assert!(hash(*seed, *b) <= hash(*seed, b0));
+        }
+        assert!(seed_aware.kept.iter().all(|b| *b < 2));
+        assert!(sybil.kept.iter().any(|b| pool.contains(b)));
+
+        // Winners join the network, the other minted identities stay offline
+        let mut is_online = vec![true; 20];
+        is_online.extend(vec![false; 200]);
+        let deploy = sybil.to_deploy(&is_online);
+        assert!(!deploy.is_empty());
+        assert!(deploy.iter().all(|b| pool.contains(b) && sybil.kept.contains(b)));
+        assert!(deploy.len() < pool.len());
+        assert!(seed_aware.to_deploy(&is_online).is_empty());
+    }
+}
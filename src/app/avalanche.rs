@@ -1,18 +1,46 @@
+use rand::RngCore;
 use structopt::StructOpt;
 use std::collections::{HashSet, HashMap};
 use std::sync::{Arc, RwLock};
 
-use crate::net::{App, PeerRef, Network};
+use crate::adversary::Behavior;
+use crate::net::{App, MessageSize, PeerRef, Network, HEADER_SIZE};
 use crate::net::Metrics as NetMetrics;
+use crate::metrics::Metric;
 use super::{brahms, sps, basalt};
+use crate::roles::{NodeRole, RoleTable};
 use crate::rps::{RPS, OracleInit};
 use crate::util::{either_or_if_both};
 
 pub enum Msg<T: App> {
-    SelfNotif,
     Pull,
     Push(bool),
-    RPSMsg(T::Msg)
+    RPS(T::Msg)
+}
+
+/// Timer of the consensus rounds, fired every time unit
+const ROUND: usize = 0;
+/// Timers of the underlying RPS are shifted by this offset
+const RPS_TIMERS: usize = 1;
+
+impl<T: App> MessageSize for Msg<T> {
+    fn size(&self) -> usize {
+        match self {
+            Msg::Pull => HEADER_SIZE,
+            Msg::Push(_) => HEADER_SIZE + 1,
+            Msg::RPS(m) => m.size(),
+        }
+    }
+}
+
+impl<T: App> Clone for Msg<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Msg::Pull => Msg::Pull,
+            Msg::Push(v) => Msg::Push(*v),
+            Msg::RPS(m) => Msg::RPS(m.clone()),
+        }
+    }
 }
 
 #[derive(Clone, Default, StructOpt, Debug)]
@@ -50,19 +78,14 @@ pub struct InitArgs {
     pub start_time: u64,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum Scenario {
+    #[default]
     Absent,
     Disagreeing,
     Adaptive,
 }
 
-impl Default for Scenario {
-    fn default() -> Self {
-        Self::Absent
-    }
-}
-
 impl std::str::FromStr for Scenario {
     type Err = &'static str;
 
@@ -91,6 +114,16 @@ pub struct Init<T: App + RPS> {
     pub shared_counter: Arc<RwLock<(usize, usize)>>,
 }
 
+impl<T: App + RPS> Clone for Init<T> {
+    fn clone(&self) -> Self {
+        Self {
+            args: self.args.clone(),
+            rps_args: self.rps_args.clone(),
+            shared_counter: self.shared_counter.clone(),
+        }
+    }
+}
+
 
 #[derive(Clone, StructOpt, Debug)]
 pub enum WhichRPS {
@@ -179,15 +212,15 @@ impl<T: App> NetMetrics for Metrics<T> {
         ret.extend(T::Metrics::headers());
         ret
     }
-    fn values(&self) -> Vec<String> {
+    fn values(&self) -> Vec<Metric> {
         let mut sc = self.shared_counter.as_ref().unwrap().write().unwrap();
         sc.0 = self.n_false;
         sc.1 = self.n_true;
         let mut ret = vec![
-            format!("{}", self.n_true),
-            format!("{}", self.n_false),
-            format!("{}", self.n_decided_true),
-            format!("{}", self.n_decided_false),
+            Metric::int(self.n_true),
+            Metric::int(self.n_false),
+            Metric::int(self.n_decided_true),
+            Metric::int(self.n_decided_false),
         ];
         ret.extend(self.rps_metrics.values());
         ret
@@ -199,15 +232,48 @@ struct NetProxy<'a, T: App> {
 }
 
 impl<'a, T: App> Network<T::Msg> for NetProxy<'a, T> {
-    fn sample_peers(&self, n: usize) -> Vec<PeerRef> {
+    fn sample_peers(&mut self, n: usize) -> Vec<PeerRef> {
         self.net.sample_peers(n)
     }
     fn send(&mut self, to: PeerRef, msg: T::Msg) {
-        self.net.send(to, Msg::RPSMsg(msg))
+        self.net.send(to, Msg::RPS(msg))
+    }
+    fn set_timer(&mut self, delay: u64, tag: usize) {
+        self.net.set_timer(delay, RPS_TIMERS + tag)
     }
     fn time(&self) -> u64 {
         self.net.time()
     }
+    fn rng(&mut self) -> &mut dyn RngCore {
+        self.net.rng()
+    }
+    fn partition(&self, peer: PeerRef) -> usize {
+        self.net.partition(peer)
+    }
+    fn role(&self, peer: PeerRef) -> NodeRole {
+        self.net.role(peer)
+    }
+    fn roles(&self) -> Arc<RoleTable> {
+        self.net.roles()
+    }
+    fn behavior(&self) -> Behavior {
+        self.net.behavior()
+    }
+    fn attack_targets(&mut self, n: usize) -> Vec<PeerRef> {
+        self.net.attack_targets(n)
+    }
+    fn advertised(&mut self, to: PeerRef, n: usize) -> Vec<PeerRef> {
+        self.net.advertised(to, n)
+    }
+    fn is_victim(&self, peer: PeerRef) -> bool {
+        self.net.is_victim(peer)
+    }
+    fn observe_seed(&mut self, slot: usize, seed: u64) {
+        self.net.observe_seed(slot, seed)
+    }
+    fn report_view(&mut self, n_byzantine: usize, view_size: usize) {
+        self.net.report_view(n_byzantine, view_size)
+    }
 }
 
 impl<T> App for Avalanche<T>
@@ -244,20 +310,17 @@ impl<T> App for Avalanche<T>
         self.params = init.args.clone();
         self.shared_counter = Some(init.shared_counter.clone());
 
-        self.is_byzantine = id < self.params.n_byzantine;
+        self.is_byzantine = net.role(id).is_byzantine();
         if !self.is_byzantine {
-            net.send(id, Msg::SelfNotif);
-            if self.my_id - self.params.n_byzantine < self.params.n_disagreeing {
-                self.value = true;
-            } else {
-                self.value = false;
-            }
+            net.set_timer(1, ROUND);
+            let correct = net.roles().correct();
+            self.value = correct[..std::cmp::min(self.params.n_disagreeing, correct.len())].contains(&id);
         }
 
     }
 
     fn handle(&mut self, net: Net<T>, from: PeerRef, msg: &Self::Msg) {
-        if let Msg::RPSMsg(mm) = msg {
+        if let Msg::RPS(mm) = msg {
             self.rps.handle(&mut NetProxy{net}, from, mm);
             return;
         }
@@ -279,37 +342,11 @@ impl<T> App for Avalanche<T>
                         }
                     }
                 }
-                Msg::RPSMsg(_) => unreachable!(),
+                Msg::RPS(_) => unreachable!(),
                 _ => (),
             }
         } else {
             match msg {
-                Msg::SelfNotif => {
-                    if self.decided.is_none() {
-                        if net.time() < self.params.start_time {
-                            self.rps.clear_samples();
-                        } else {
-                            self.rps_set.extend(self.rps.get_samples());
-                        }
-
-                        if self.timeout == 0
-                            && net.time() >= self.params.start_time
-                            && self.rps_set.len() >= self.params.k
-                        {
-                            self.query_set.clear();
-                            self.reply_set.clear();
-                            while self.query_set.len() < self.params.k && !self.rps_set.is_empty() {
-                                let p = self.rps_set.pop().unwrap();
-                                self.query_set.insert(p);
-                                net.send(p, Msg::Pull);
-                            }
-                            self.timeout = 2;
-                        } else if self.timeout > 0 {
-                            self.timeout -= 1;
-                        }
-                    }
-                    net.send(self.my_id, Msg::SelfNotif);
-                },
                 Msg::Pull => {
                     if let Some(d) = self.decided {
                         net.send(from, Msg::Push(d))
@@ -323,10 +360,10 @@ impl<T> App for Avalanche<T>
                     }
                     if self.reply_set.len() >= self.params.alpha_k {
                         let count_true = self.reply_set.iter()
-                            .filter(|(_, v)| **v == true)
+                            .filter(|(_, v)| **v)
                             .count();
                         let count_false = self.reply_set.iter()
-                            .filter(|(_, v)| **v == false)
+                            .filter(|(_, v)| !**v)
                             .count();
                         let mut proposal = None;
                         let thresh = self.params.beta * self.reply_set.len() as f32;
@@ -345,7 +382,7 @@ impl<T> App for Avalanche<T>
                                 }
                             } else {
                                 if self.counter > 0 {
-                                    self.counter = self.counter - 1;
+                                    self.counter -= 1;
                                 }
                                 if self.counter == 0 {
                                     self.value = prop;
@@ -354,8 +391,41 @@ impl<T> App for Avalanche<T>
                         }
                     }
                 }
-                Msg::RPSMsg(_) => unreachable!(),
+                Msg::RPS(_) => unreachable!(),
+            }
+        }
+    }
+
+    fn on_timer(&mut self, net: Net<T>, tag: usize) {
+        if tag >= RPS_TIMERS {
+            self.rps.on_timer(&mut NetProxy{net}, tag - RPS_TIMERS);
+            return;
+        }
+        if !self.is_byzantine {
+            if self.decided.is_none() {
+                if net.time() < self.params.start_time {
+                    self.rps.clear_samples();
+                } else {
+                    self.rps_set.extend(self.rps.get_samples(net.rng()));
+                }
+
+                if self.timeout == 0
+                    && net.time() >= self.params.start_time
+                    && self.rps_set.len() >= self.params.k
+                {
+                    self.query_set.clear();
+                    self.reply_set.clear();
+                    while self.query_set.len() < self.params.k && !self.rps_set.is_empty() {
+                        let p = self.rps_set.pop().unwrap();
+                        self.query_set.insert(p);
+                        net.send(p, Msg::Pull);
+                    }
+                    self.timeout = 2;
+                } else if self.timeout > 0 {
+                    self.timeout -= 1;
+                }
             }
+            net.set_timer(1, ROUND);
         }
     }
 
@@ -1,33 +1,50 @@
-use rand::{thread_rng, Rng};
+use rand::{Rng, RngCore};
+use std::collections::BTreeMap;
 use structopt::StructOpt;
 
-use crate::net::{App, PeerRef, Network};
+use crate::adversary::Behavior;
+use crate::net::{App, MessageSize, PeerRef, Network, HEADER_SIZE, PEER_SIZE};
 use crate::net::Metrics as NetMetrics;
-use crate::util::{either_or_if_both, hash, sample_nocopy};
+use crate::metrics::{add_histogram, histogram_of, Metric};
+use crate::util::{either_or_if_both, hash};
 use crate::rps::RPS;
-use crate::graph::ByzConnGraph;
+use crate::graph::{ByzConnGraph, GraphParams};
+use crate::victims::{update_isolation, VictimStats};
 
 
+#[derive(Clone)]
 pub enum Msg {
-    SelfNotif,
     Pull,
     Push(Vec<PeerRef>),
 }
 
+impl MessageSize for Msg {
+    fn size(&self) -> usize {
+        match self {
+            Msg::Pull => HEADER_SIZE,
+            Msg::Push(peers) => HEADER_SIZE + peers.len() * PEER_SIZE,
+        }
+    }
+}
+
+/// Timer of the gossip rounds, fired every time unit
+const ROUND: usize = 0;
+
 #[derive(Clone, Default, StructOpt, Debug)]
 pub struct Init {
     /// Number of Byzantine nodes
     #[structopt(short = "t", long = "num-byzantines")]
     pub n_byzantine: usize,
 
+    /// Deprecated alias of `--adversary lurking:<time>`: Byzantine nodes
+    /// answer requests but push nothing until this time
+    #[structopt(short = "s", long = "attack-start-time")]
+    pub attack_start_time: Option<u64>,
+
     /// Byzantine flood factor
     #[structopt(short = "f", long = "byzantine-flood-factor")]
     pub byzantine_flood_factor: usize,
 
-    /// Byzantine attack start time
-    #[structopt(short = "s", long = "attack-start-time", default_value = "0")]
-    pub attack_start_time: u64,
-
     /// Replacement frequency: replace k neighbor every r (this paramter) time units
     #[structopt(short = "r", long = "replacement-frequency")]
     pub replacement_frequency: Option<u64>,
@@ -51,6 +68,9 @@ pub struct Init {
     /// Enable detailed graph statistics
     #[structopt(short = "G", long = "graph-stats")]
     pub graph_stats: bool,
+
+    #[structopt(flatten)]
+    pub graph: GraphParams,
 }
 
 pub struct Basalt {
@@ -65,6 +85,8 @@ pub struct Basalt {
 
     n_received: usize,
     n_byzantine_received: usize,
+
+    isolated_at: Option<u64>,
 }
 
 struct ViewEntry {
@@ -83,6 +105,12 @@ pub struct Metrics {
     min_byzantine_neighbors: Option<i64>,
     max_byzantine_neighbors: Option<i64>,
     n_isolated: usize,
+    byzantine_neighbors_hist: BTreeMap<u64, u64>,
+
+    n_view_entries: usize,
+    n_own_partition: usize,
+
+    victims: VictimStats,
 
     graph: ByzConnGraph,
 }
@@ -97,6 +125,10 @@ impl NetMetrics for Metrics {
             min_byzantine_neighbors: None,
             max_byzantine_neighbors: None,
             n_isolated: 0,
+            byzantine_neighbors_hist: BTreeMap::new(),
+            n_view_entries: 0,
+            n_own_partition: 0,
+            victims: VictimStats::new(),
             graph: ByzConnGraph::new(),
         }
     }
@@ -116,6 +148,11 @@ impl NetMetrics for Metrics {
             &other.min_byzantine_neighbors,
             |a, b| std::cmp::min(*a, *b));
         self.n_isolated += other.n_isolated;
+        add_histogram(&mut self.byzantine_neighbors_hist, &other.byzantine_neighbors_hist);
+
+        self.n_view_entries += other.n_view_entries;
+        self.n_own_partition += other.n_own_partition;
+        self.victims.combine(&other.victims);
 
         self.graph.combine(&other.graph);
     }
@@ -128,43 +165,60 @@ impl NetMetrics for Metrics {
             "min",
             "max",
             "n_isolated",
+            "pOwnPart",
+            "victByz",
+            "victIsolT",
             "cluscoeff",
             "MPL",
+            "pUnreach",
+            "diam",
+            "n_wcc", "n_scc", "maxWcc", "maxScc", "n_unreach",
             "id_min", "id_d1", "id_q1", "id_med", "id_q3", "id_d9", "id_max",
+            "byzNHist",
         ]
     }
-    fn values(&self) -> Vec<String> {
+    fn values(&self) -> Vec<Metric> {
         // Clustering coefficient
         let cluscoeff = self.graph.clustering_coeff();
 
         // In-degree quartiles (for correct nodes)
-        let ind = self.graph.indegree_dist(self.n_procs);
+        let ind = self.graph.indegree_dist();
+
+        // Average path length, diameter estimation and unreachable pairs
+        let (mpl, diameter, unreachable) = self.graph.path_lengths();
 
-        // Average path length estimation
-        let mpl = self.graph.mean_path_length(self.n_procs);
+        // Components of the correct nodes
+        let comp = self.graph.components();
 
         vec![
-            format!("{:.2}",
-                   (self.n_received as f32) / (self.n_procs as f32)),
-            format!("{:.2}",
-                   (self.n_byzantine_received as f32) / (self.n_procs as f32)),
-            format!("{:.4}",
-                   (self.n_byzantine_received as f32) / (self.n_received as f32)),
-            format!("{:.2}",
-                   (self.n_byzantine_neighbors as f32) / (self.n_procs as f32)),
-            format!("{}", self.min_byzantine_neighbors.unwrap_or(-1)),
-            format!("{}", self.max_byzantine_neighbors.unwrap_or(-1)),
-            format!("{}", self.n_isolated),
-
-            format!("{:.4}", cluscoeff),
-            format!("{:.4}", mpl),
-            format!("{}", ind[0]),
-            format!("{}", ind[ind.len()/10]),
-            format!("{}", ind[ind.len()/4]),
-            format!("{}", ind[ind.len()/2]),
-            format!("{}", ind[3*ind.len()/4]),
-            format!("{}", ind[9*ind.len()/10]),
-            format!("{}", ind[ind.len()-1]),
+            Metric::ratio(self.n_received, self.n_procs, 2),
+            Metric::ratio(self.n_byzantine_received, self.n_procs, 2),
+            Metric::ratio(self.n_byzantine_received, self.n_received, 4),
+            Metric::ratio(self.n_byzantine_neighbors, self.n_procs, 2),
+            Metric::optional(self.min_byzantine_neighbors.map(Metric::Int)),
+            Metric::optional(self.max_byzantine_neighbors.map(Metric::Int)),
+            Metric::int(self.n_isolated),
+            Metric::ratio(self.n_own_partition, self.n_view_entries, 4),
+            self.victims.byzantine_fractions(),
+            self.victims.isolation_times(),
+
+            Metric::Float(cluscoeff, 4),
+            Metric::Float(mpl, 4),
+            Metric::Float(unreachable, 4),
+            Metric::int(diameter),
+            Metric::int(comp.n_weak),
+            Metric::int(comp.n_strong),
+            Metric::int(comp.largest_weak),
+            Metric::int(comp.largest_strong),
+            Metric::int(comp.n_unreachable),
+            Metric::int(ind[0]),
+            Metric::int(ind[ind.len()/10]),
+            Metric::int(ind[ind.len()/4]),
+            Metric::int(ind[ind.len()/2]),
+            Metric::int(ind[3*ind.len()/4]),
+            Metric::int(ind[9*ind.len()/10]),
+            Metric::int(ind[ind.len()-1]),
+            Metric::Histogram(self.byzantine_neighbors_hist.clone()),
         ]
     }
 }
@@ -198,7 +252,7 @@ impl Basalt {
         }
     }
 
-    fn get_exchange_peer(&mut self, rng: &mut rand::ThreadRng) -> PeerRef {
+    fn get_exchange_peer(&mut self, rng: &mut dyn RngCore) -> PeerRef {
         if self.params.use_hit_counter {
             let mut ret = 0;
             for i in 1..self.view.len() {
@@ -230,6 +284,8 @@ impl App for Basalt {
 
             n_received: 0,
             n_byzantine_received: 0,
+
+            isolated_at: None,
         }
     }
     
@@ -237,75 +293,45 @@ impl App for Basalt {
         self.my_id = id;
         self.params = init.clone();
 
-        self.is_byzantine = id < init.n_byzantine;
-        if !self.is_byzantine {
-            let mut rng = thread_rng();
-            self.view = (0..self.params.view_size)
-                .map(|_| ViewEntry{
-                    seed: rng.gen_range(0, std::u64::MAX),
-                    peer: id,
-                    hits: 1
-                }).collect();
-
-            let initial_samples = net.sample_peers(self.params.initial_uniform_samples);
-            self.update_samples(&initial_samples[..]);
+        self.is_byzantine = net.role(id).is_byzantine();
+        self.view = (0..self.params.view_size)
+            .map(|_| ViewEntry{
+                seed: net.rng().gen_range(0, u64::MAX),
+                peer: id,
+                hits: 1
+            }).collect();
+        for (i, entry) in self.view.iter().enumerate() {
+            net.observe_seed(i, entry.seed);
         }
-        net.send(id, Msg::SelfNotif);
+
+        let initial_samples = net.sample_peers(self.params.initial_uniform_samples);
+        self.update_samples(&initial_samples[..]);
+        net.set_timer(1, ROUND);
     }
 
     fn handle(&mut self, net: Net, from: PeerRef, msg: &Self::Msg) {
-        if self.is_byzantine {
-            let mut byzantines = (0..self.params.n_byzantine).collect::<Vec<_>>();
+        if self.is_byzantine && net.behavior() != Behavior::Honest {
+            // Requests are answered unless the adversary is silent
+            let answer = net.behavior() != Behavior::Silent;
             match msg {
-                Msg::SelfNotif => {
-                    net.send(self.my_id, Msg::SelfNotif);
-                    if net.time() >= self.params.attack_start_time {
-                        net.sample_peers(self.params.byzantine_flood_factor)
-                            .iter()
-                            .for_each(|p| net.send(*p, Msg::Push(sample_nocopy(&mut byzantines[..], self.params.view_size))));
-                    }
-                },
-                Msg::Pull => {
-                    net.send(from, Msg::Push(sample_nocopy(&mut byzantines[..], self.params.view_size)));
+                Msg::Pull if answer => {
+                    let pushed = net.advertised(from, self.params.view_size);
+                    net.send(from, Msg::Push(pushed));
                 },
                 _ => (),
             }
         } else {
-            let mut rng = thread_rng();
             let view = self.view.iter()
                 .map(|entry| entry.peer)
                 .collect::<Vec<_>>();
             match msg {
-                Msg::SelfNotif => {
-                    if let Some(rf) = self.params.replacement_frequency {
-                        if (self.my_id as u64 + net.time()) % rf == 0 {
-                            for k in 0..self.params.replacement_count {
-                                let i_replace = ((net.time() / rf) as usize * self.params.replacement_count + k) % self.view.len();
-                                if self.out_samples.len() < 200 {
-                                    self.out_samples.push(self.view[i_replace].peer);
-                                }
-                                self.view[i_replace].seed = rng.gen_range(0, std::u64::MAX);
-                                self.view[i_replace].hits = 1;
-                                self.update_sample(i_replace, &view[..]);
-                            }
-                        }
-                    }
-
-                    let pull_from = self.get_exchange_peer(&mut rng);
-                    net.send(pull_from, Msg::Pull);
-
-                    let push_to = self.get_exchange_peer(&mut rng);
-                    net.send(push_to, Msg::Push(view.clone()));
-
-                    net.send(self.my_id, Msg::SelfNotif);
-                },
                 Msg::Pull => {
                     net.send(from, Msg::Push(view.clone()));
                 },
                 Msg::Push(lst) => {
                     self.n_received += lst.len();
                     self.n_byzantine_received += lst.iter()
-                        .filter(|x| **x < self.params.n_byzantine)
+                        .filter(|x| net.role(**x).is_byzantine())
                         .count();
                     self.update_samples(&lst[..]);
                     self.update_samples(&[from]);
@@ -314,25 +340,74 @@ impl App for Basalt {
         }
     }
 
-    fn metrics(&mut self, _net: Net) -> Self::Metrics {
+    fn on_timer(&mut self, net: Net, _tag: usize) {
+        if self.is_byzantine && net.behavior() != Behavior::Honest {
+            if net.behavior() == Behavior::Malicious {
+                for p in net.attack_targets(self.params.byzantine_flood_factor) {
+                    let pushed = net.advertised(p, self.params.view_size);
+                    net.send(p, Msg::Push(pushed));
+                }
+            }
+        } else {
+            let view = self.view.iter()
+                .map(|entry| entry.peer)
+                .collect::<Vec<_>>();
+            if let Some(rf) = self.params.replacement_frequency {
+                if (self.my_id as u64 + net.time()).is_multiple_of(rf) {
+                    for k in 0..self.params.replacement_count {
+                        let i_replace = ((net.time() / rf) as usize * self.params.replacement_count + k) % self.view.len();
+                        if self.out_samples.len() < 200 {
+                            self.out_samples.push(self.view[i_replace].peer);
+                        }
+                        self.view[i_replace].seed = net.rng().gen_range(0, u64::MAX);
+                        net.observe_seed(i_replace, self.view[i_replace].seed);
+                        self.view[i_replace].hits = 1;
+                        self.update_sample(i_replace, &view[..]);
+                    }
+                }
+            }
+
+            let pull_from = self.get_exchange_peer(net.rng());
+            net.send(pull_from, Msg::Pull);
+
+            let push_to = self.get_exchange_peer(net.rng());
+            net.send(push_to, Msg::Push(view));
+        }
+        net.set_timer(1, ROUND);
+    }
+
+    fn metrics(&mut self, net: Net) -> Self::Metrics {
         if self.is_byzantine {
             let mut metrics = Self::Metrics::empty();
 
             if self.params.graph_stats {
-                let neighs = (0..self.params.n_byzantine).collect::<Vec<_>>();
-                metrics.graph = ByzConnGraph::peer_new(self.params.n_byzantine,
+                let neighs = net.roles().byzantine();
+                metrics.graph = ByzConnGraph::peer_new(net.roles(),
+                                                       &self.params.graph,
                                                        self.my_id,
+                                                       net.rng().gen(),
                                                        neighs);
             }
 
             metrics
         } else {
             let nbn = self.view.iter()
-                .filter(|entry| entry.peer < self.params.n_byzantine).count();
+                .filter(|entry| net.role(entry.peer).is_byzantine()).count();
+            net.report_view(nbn, self.view.len());
+            let my_partition = net.partition(self.my_id);
+            let n_own_partition = self.view.iter()
+                .filter(|entry| net.partition(entry.peer) == my_partition).count();
+
+            let victims = if net.is_victim(self.my_id) {
+                update_isolation(&mut self.isolated_at, nbn == self.view.len(), net.time());
+                VictimStats::peer_new(self.my_id, nbn, self.view.len(), self.isolated_at)
+            } else {
+                VictimStats::new()
+            };
 
             let graph = if self.params.graph_stats {
                 let neighs = self.view.iter().map(|x| x.peer).collect::<Vec<_>>();
-                ByzConnGraph::peer_new(self.params.n_byzantine, self.my_id, neighs)
+                ByzConnGraph::peer_new(net.roles(), &self.params.graph, self.my_id, net.rng().gen(), neighs)
             } else {
                 ByzConnGraph::new()
             };
@@ -343,6 +418,10 @@ impl App for Basalt {
                 n_byzantine_received: self.n_byzantine_received,
                 n_byzantine_neighbors: nbn,
                 n_isolated: if nbn == self.view.len() { 1 } else { 0 },
+                byzantine_neighbors_hist: histogram_of(nbn),
+                n_view_entries: self.view.len(),
+                n_own_partition,
+                victims,
                 min_byzantine_neighbors: Some(nbn as i64),
                 max_byzantine_neighbors: Some(nbn as i64),
                 graph,
@@ -355,8 +434,8 @@ impl App for Basalt {
 }
 
 impl RPS for Basalt {
-    fn get_samples(&mut self) -> Vec<PeerRef> {
-        std::mem::replace(&mut self.out_samples, Vec::new())
+    fn get_samples(&mut self, _rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        std::mem::take(&mut self.out_samples)
     }
     fn clear_samples(&mut self) {
         self.out_samples.clear();
@@ -1,33 +1,50 @@
-use rand::{thread_rng, Rng};
+use rand::{Rng, RngCore};
+use std::collections::BTreeMap;
 use structopt::StructOpt;
 
-use crate::net::{App, PeerRef, Network};
+use crate::adversary::Behavior;
+use crate::net::{App, MessageSize, PeerRef, Network, HEADER_SIZE, PEER_SIZE};
 use crate::net::Metrics as NetMetrics;
-use crate::util::{either_or_if_both, hash, sample, sample_nocopy};
+use crate::metrics::{add_histogram, histogram_of, Metric};
+use crate::util::{either_or_if_both, hash, sample};
 use crate::rps::RPS;
-use crate::graph::ByzConnGraph;
+use crate::graph::{ByzConnGraph, GraphParams};
+use crate::victims::{update_isolation, VictimStats};
 
+#[derive(Clone)]
 pub enum Msg {
-    SelfNotif,
     PullRequest,
     PullReply(Vec<PeerRef>),
     PushRequest,
 }
 
+impl MessageSize for Msg {
+    fn size(&self) -> usize {
+        match self {
+            Msg::PullRequest | Msg::PushRequest => HEADER_SIZE,
+            Msg::PullReply(peers) => HEADER_SIZE + peers.len() * PEER_SIZE,
+        }
+    }
+}
+
+/// Timer of the gossip rounds, fired every time unit
+const ROUND: usize = 0;
+
 #[derive(Clone, Default, StructOpt, Debug)]
 pub struct Init {
     /// Number of Byzantine nodes
     #[structopt(short = "t", long = "num-byzantines")]
     pub n_byzantine: usize,
 
+    /// Deprecated alias of `--adversary lurking:<time>`: Byzantine nodes
+    /// answer requests but push nothing until this time
+    #[structopt(short = "s", long = "attack-start-time")]
+    pub attack_start_time: Option<u64>,
+
     /// Byzantine flood factor
     #[structopt(short = "f", long = "byzantine-flood-factor")]
     pub byzantine_flood_factor: usize,
 
-    /// Byzantine attack start time
-    #[structopt(short = "s", long = "attack-start-time", default_value = "0")]
-    pub attack_start_time: u64,
-
     /// Replacement frequency: replace k samples every r (this paramter) time units
     #[structopt(short = "r", long = "replacement-frequency")]
     pub replacement_frequency: Option<u64>,
@@ -47,22 +64,20 @@ pub struct Init {
     /// Enable detailed graph statistics
     #[structopt(short = "G", long = "graph-stats", default_value = "nograph")]
     pub graph_stats: WhichGraphStats,
+
+    #[structopt(flatten)]
+    pub graph: GraphParams,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum WhichGraphStats {
+    #[default]
     NoGraph,
     View,
     Samples,
     ViewSamples,
 }
 
-impl Default for WhichGraphStats {
-    fn default() -> Self {
-        Self::NoGraph
-    }
-}
-
 impl std::str::FromStr for WhichGraphStats {
     type Err = &'static str;
 
@@ -93,6 +108,8 @@ pub struct Brahms {
 
     n_received: usize,
     n_byzantine_received: usize,
+
+    isolated_at: Option<u64>,
 }
 
 pub struct Metrics {
@@ -103,6 +120,12 @@ pub struct Metrics {
     
     n_byzantine_neighbors: usize,
     n_isolated: usize,
+    byzantine_neighbors_hist: BTreeMap<u64, u64>,
+
+    n_view_entries: usize,
+    n_own_partition: usize,
+
+    victims: VictimStats,
 
     n_byzantine_samples: usize,
     min_byzantine_samples: Option<i64>,
@@ -123,6 +146,10 @@ impl NetMetrics for Metrics {
             n_received: 0,
             n_byzantine_neighbors: 0,
             n_isolated: 0,
+            byzantine_neighbors_hist: BTreeMap::new(),
+            n_view_entries: 0,
+            n_own_partition: 0,
+            victims: VictimStats::new(),
             n_byzantine_samples: 0,
             min_byzantine_samples: None,
             max_byzantine_samples: None,
@@ -139,6 +166,11 @@ impl NetMetrics for Metrics {
 
         self.n_byzantine_neighbors += other.n_byzantine_neighbors;
         self.n_isolated += other.n_isolated;
+        add_histogram(&mut self.byzantine_neighbors_hist, &other.byzantine_neighbors_hist);
+
+        self.n_view_entries += other.n_view_entries;
+        self.n_own_partition += other.n_own_partition;
+        self.victims.combine(&other.victims);
 
         self.n_byzantine_samples += other.n_byzantine_samples;
         self.max_byzantine_samples = either_or_if_both(
@@ -162,6 +194,9 @@ impl NetMetrics for Metrics {
             "pByzRecv",
             "avgByzN",
             "n_isolated",
+            "pOwnPart",
+            "victByz",
+            "victIsolT",
             "avgByzSamp",
             "min",
             "max",
@@ -169,45 +204,58 @@ impl NetMetrics for Metrics {
             "n_fbi",
             "cluscoeff",
             "MPL",
+            "pUnreach",
+            "diam",
+            "n_wcc", "n_scc", "maxWcc", "maxScc", "n_unreach",
             "id_min", "id_d1", "id_q1", "id_med", "id_q3", "id_d9", "id_max",
+            "byzNHist",
         ]
     }
-    fn values(&self) -> Vec<String> {
+    fn values(&self) -> Vec<Metric> {
         // Clustering coefficient
         let cluscoeff = self.graph.clustering_coeff();
 
         // In-degree quartiles (for correct nodes)
-        let ind = self.graph.indegree_dist(self.n_procs);
+        let ind = self.graph.indegree_dist();
 
-        // Average path length estimation
-        let mpl = self.graph.mean_path_length(self.n_procs);
+        // Average path length, diameter estimation and unreachable pairs
+        let (mpl, diameter, unreachable) = self.graph.path_lengths();
+
+        // Components of the correct nodes
+        let comp = self.graph.components();
 
         vec![
-            format!("{:.2}",
-                   (self.n_received as f32) / (self.n_procs as f32)),
-            format!("{:.2}",
-                   (self.n_byzantine_received as f32) / (self.n_procs as f32)),
-            format!("{:.4}",
-                   (self.n_byzantine_received as f32) / (self.n_received as f32)),
-            format!("{:.2}",
-                   (self.n_byzantine_neighbors as f32) / (self.n_procs as f32)),
-            format!("{}", self.n_isolated),
-            format!("{:.2}",
-                (self.n_byzantine_samples as f32) / (self.n_procs as f32)),
-            format!("{}", self.min_byzantine_samples.unwrap_or(-1)),
-            format!("{}", self.max_byzantine_samples.unwrap_or(-1)),
-            format!("{}", self.n_fullbyz),
-            format!("{}", self.n_fbi),
-
-            format!("{:.4}", cluscoeff),
-            format!("{:.4}", mpl),
-            format!("{}", ind[0]),
-            format!("{}", ind[ind.len()/10]),
-            format!("{}", ind[ind.len()/4]),
-            format!("{}", ind[ind.len()/2]),
-            format!("{}", ind[3*ind.len()/4]),
-            format!("{}", ind[9*ind.len()/10]),
-            format!("{}", ind[ind.len()-1]),
+            Metric::ratio(self.n_received, self.n_procs, 2),
+            Metric::ratio(self.n_byzantine_received, self.n_procs, 2),
+            Metric::ratio(self.n_byzantine_received, self.n_received, 4),
+            Metric::ratio(self.n_byzantine_neighbors, self.n_procs, 2),
+            Metric::int(self.n_isolated),
+            Metric::ratio(self.n_own_partition, self.n_view_entries, 4),
+            self.victims.byzantine_fractions(),
+            self.victims.isolation_times(),
+            Metric::ratio(self.n_byzantine_samples, self.n_procs, 2),
+            Metric::optional(self.min_byzantine_samples.map(Metric::Int)),
+            Metric::optional(self.max_byzantine_samples.map(Metric::Int)),
+            Metric::int(self.n_fullbyz),
+            Metric::int(self.n_fbi),
+
+            Metric::Float(cluscoeff, 4),
+            Metric::Float(mpl, 4),
+            Metric::Float(unreachable, 4),
+            Metric::int(diameter),
+            Metric::int(comp.n_weak),
+            Metric::int(comp.n_strong),
+            Metric::int(comp.largest_weak),
+            Metric::int(comp.largest_strong),
+            Metric::int(comp.n_unreachable),
+            Metric::int(ind[0]),
+            Metric::int(ind[ind.len()/10]),
+            Metric::int(ind[ind.len()/4]),
+            Metric::int(ind[ind.len()/2]),
+            Metric::int(ind[3*ind.len()/4]),
+            Metric::int(ind[9*ind.len()/10]),
+            Metric::int(ind[ind.len()-1]),
+            Metric::Histogram(self.byzantine_neighbors_hist.clone()),
         ]
     }
 }
@@ -257,6 +305,8 @@ impl App for Brahms {
 
             n_received: 0,
             n_byzantine_received: 0,
+
+            isolated_at: None,
         }
     }
     
@@ -264,101 +314,45 @@ impl App for Brahms {
         self.my_id = id;
         self.params = init.clone();
 
-        self.is_byzantine = id < init.n_byzantine;
-        if !self.is_byzantine {
-            let view = net.sample_peers(self.params.view_size);
+        self.is_byzantine = net.role(id).is_byzantine();
+        let view = net.sample_peers(self.params.view_size);
 
-            let mut rng = thread_rng();
-            self.sample_view = (0..self.params.sample_view_size)
-                .map(|_| (rng.gen_range(0, std::u64::MAX), None)).collect();
-            self.update_samples(&view[..]);
-            self.view = view;
+        self.sample_view = (0..self.params.sample_view_size)
+            .map(|_| (net.rng().gen_range(0, u64::MAX), None)).collect();
+        for (i, (seed, _)) in self.sample_view.iter().enumerate() {
+            net.observe_seed(i, *seed);
         }
-        net.send(id, Msg::SelfNotif);
+        self.update_samples(&view[..]);
+        self.view = view;
+        net.set_timer(1, ROUND);
     }
 
     fn handle(&mut self, net: Net, from: PeerRef, msg: &Self::Msg) {
-        if self.is_byzantine {
-            let mut byzantines = (0..self.params.n_byzantine).collect::<Vec<_>>();
+        if self.is_byzantine && net.behavior() != Behavior::Honest {
+            // Requests are answered unless the adversary is silent
+            let answer = net.behavior() != Behavior::Silent;
             match msg {
-                Msg::SelfNotif => {
-                    net.send(self.my_id, Msg::SelfNotif);
-                    if net.time() >= self.params.attack_start_time {
-                        net.sample_peers(self.params.byzantine_flood_factor)
-                            .iter()
-                            .for_each(|p| net.send(*p, Msg::PushRequest));
-                    }
-                },
-                Msg::PullRequest => {
-                    net.send(from, Msg::PullReply(sample_nocopy(&mut byzantines[..], self.params.view_size)));
+                Msg::PullRequest if answer => {
+                    let reply = net.advertised(from, self.params.view_size);
+                    net.send(from, Msg::PullReply(reply));
                 },
                 _ => (),
             }
         } else {
             match msg {
-                Msg::SelfNotif => {
-                    if let Some(rf) = self.params.replacement_frequency {
-                        if (self.my_id as u64 + net.time()) % rf == 0 {
-                            let mut rng = thread_rng();
-                            let view = self.view.clone();
-                            let sample_view = self.sample_view.iter()
-                                .filter(|(_, x)| x.is_some())
-                                .map(|(_, x)| x.unwrap())
-                                .collect::<Vec<_>>();
-                            for k in 0..self.params.replacement_count {
-                                let i_replace = ((net.time() / rf) as usize * self.params.replacement_count + k) % self.sample_view.len();
-                                if let Some(sample) = self.sample_view[i_replace].1 {
-                                    if self.out_samples.len() < 200 {
-                                        self.out_samples.push(sample);
-                                    }
-                                }
-                                self.sample_view[i_replace].0 = rng.gen_range(0, std::u64::MAX);
-                                self.update_sample(i_replace, &view[..]);
-                                self.update_sample(i_replace, &sample_view[..]);
-                            }
-                        }
-                    }
-
-                    if !self.v_push.is_empty() && !self.v_pull.is_empty() {
-                        let v_push = std::mem::replace(&mut self.v_push, Vec::new());
-                        let v_pull = std::mem::replace(&mut self.v_pull, Vec::new());
-
-                        let mut view = sample(&v_push[..], self.params.view_size / 3);
-                        view.extend(sample(&v_pull[..], self.params.view_size / 3));
-
-                        let samples_peer = self.sample_view.iter()
-                            .filter(|(_, x)| x.is_some())
-                            .map(|(_, x)| x.unwrap())
-                            .collect::<Vec<_>>();
-                        view.extend(sample(&samples_peer[..], self.params.view_size - view.len()));
-                        view.extend(sample(&self.view[..], self.params.view_size - view.len()));
-                        self.view = view;
-
-                        self.update_samples(&v_push[..]);
-                        self.update_samples(&v_pull[..]);
-                    }
-
-                    sample(&self.view[..], 1).iter()
-                        .for_each(|p| net.send(*p, Msg::PushRequest));
-
-                    sample(&self.view[..], 1).iter()
-                        .for_each(|p| net.send(*p, Msg::PullRequest));
-
-                    net.send(self.my_id, Msg::SelfNotif);
-                },
                 Msg::PullRequest => {
                     net.send(from, Msg::PullReply(self.view.clone()));
                 },
                 Msg::PullReply(lst) => {
                     self.n_received += lst.len();
                     self.n_byzantine_received += lst.iter()
-                        .filter(|x| **x < self.params.n_byzantine)
+                        .filter(|x| net.role(**x).is_byzantine())
                         .count();
                     self.v_pull.extend(lst);
                 },
                 Msg::PushRequest => {
                     self.n_received += 1;
-                    if from < self.params.n_byzantine {
+                    if net.role(from).is_byzantine() {
                         self.n_byzantine_received += 1;
                     }
                     self.v_push.push(from);
@@ -367,42 +361,117 @@ impl App for Brahms {
         }
     }
 
-    fn metrics(&mut self, _net: Net) -> Self::Metrics {
+    fn on_timer(&mut self, net: Net, _tag: usize) {
+        if self.is_byzantine && net.behavior() != Behavior::Honest {
+            if net.behavior() == Behavior::Malicious {
+                net.attack_targets(self.params.byzantine_flood_factor)
+                    .iter()
+                    .for_each(|p| net.send(*p, Msg::PushRequest));
+            }
+        } else {
+            if let Some(rf) = self.params.replacement_frequency {
+                if (self.my_id as u64 + net.time()).is_multiple_of(rf) {
+                    let view = self.view.clone();
+                    let sample_view = self.sample_view.iter()
+                        .filter(|(_, x)| x.is_some())
+                        .map(|(_, x)| x.unwrap())
+                        .collect::<Vec<_>>();
+                    for k in 0..self.params.replacement_count {
+                        let i_replace = ((net.time() / rf) as usize * self.params.replacement_count + k) % self.sample_view.len();
+                        if let Some(sample) = self.sample_view[i_replace].1 {
+                            if self.out_samples.len() < 200 {
+                                self.out_samples.push(sample);
+                            }
+                        }
+                        self.sample_view[i_replace].0 = net.rng().gen_range(0, u64::MAX);
+                        net.observe_seed(i_replace, self.sample_view[i_replace].0);
+                        self.update_sample(i_replace, &view[..]);
+                        self.update_sample(i_replace, &sample_view[..]);
+                    }
+                }
+            }
+
+            if !self.v_push.is_empty() && !self.v_pull.is_empty() {
+                let v_push = std::mem::take(&mut self.v_push);
+                let v_pull = std::mem::take(&mut self.v_pull);
+
+                let mut view = sample(&v_push[..], self.params.view_size / 3, net.rng());
+                view.extend(sample(&v_pull[..], self.params.view_size / 3, net.rng()));
+
+                let samples_peer = self.sample_view.iter()
+                    .filter(|(_, x)| x.is_some())
+                    .map(|(_, x)| x.unwrap())
+                    .collect::<Vec<_>>();
+                let n_from_samples = self.params.view_size - view.len();
+                view.extend(sample(&samples_peer[..], n_from_samples, net.rng()));
+                let n_from_view = self.params.view_size - view.len();
+                view.extend(sample(&self.view[..], n_from_view, net.rng()));
+                self.view = view;
+
+                self.update_samples(&v_push[..]);
+                self.update_samples(&v_pull[..]);
+            }
+
+            for p in sample(&self.view[..], 1, net.rng()) {
+                net.send(p, Msg::PushRequest);
+            }
+
+            for p in sample(&self.view[..], 1, net.rng()) {
+                net.send(p, Msg::PullRequest);
+            }
+        }
+        net.set_timer(1, ROUND);
+    }
+
+    fn metrics(&mut self, net: Net) -> Self::Metrics {
         if self.is_byzantine {
             let mut metrics = Self::Metrics::empty();
 
             if self.params.graph_stats != WhichGraphStats::NoGraph {
-                let neighs = (0..self.params.n_byzantine).collect::<Vec<_>>();
-                metrics.graph = ByzConnGraph::peer_new(self.params.n_byzantine,
+                let neighs = net.roles().byzantine();
+                metrics.graph = ByzConnGraph::peer_new(net.roles(),
+                                                       &self.params.graph,
                                                        self.my_id,
+                                                       net.rng().gen(),
                                                        neighs);
             }
 
             metrics
         } else {
-            let nbn = self.view.iter().filter(|x| **x < self.params.n_byzantine).count();
+            let nbn = self.view.iter().filter(|x| net.role(**x).is_byzantine()).count();
+            net.report_view(nbn, self.view.len());
+            let my_partition = net.partition(self.my_id);
+            let n_own_partition = self.view.iter()
+                .filter(|x| net.partition(**x) == my_partition).count();
             let samp = self.sample_view.iter()
                 .filter(|(_, x)| x.is_some());
             let nsamp = samp.clone().count();
-            let nbs = samp.filter(|(_, x)| x.unwrap() < self.params.n_byzantine).count();
+            let nbs = samp.filter(|(_, x)| net.role(x.unwrap()).is_byzantine()).count();
+
+            let victims = if net.is_victim(self.my_id) {
+                update_isolation(&mut self.isolated_at, nbn == self.view.len(), net.time());
+                VictimStats::peer_new(self.my_id, nbn, self.view.len(), self.isolated_at)
+            } else {
+                VictimStats::new()
+            };
 
             let graph = match self.params.graph_stats {
                 WhichGraphStats::NoGraph => ByzConnGraph::new(),
                 WhichGraphStats::View => {
                     let neighs = self.view.clone();
-                    ByzConnGraph::peer_new(self.params.n_byzantine, self.my_id, neighs)
+                    ByzConnGraph::peer_new(net.roles(), &self.params.graph, self.my_id, net.rng().gen(), neighs)
                 }
                 WhichGraphStats::Samples => {
                     let neighs = self.sample_view.iter().filter(|(_, x)| x.is_some())
                                   .map(|(_, x)| x.unwrap())
                                   .collect::<Vec<_>>();
-                    ByzConnGraph::peer_new(self.params.n_byzantine, self.my_id, neighs)
+                    ByzConnGraph::peer_new(net.roles(), &self.params.graph, self.my_id, net.rng().gen(), neighs)
                 }
                 WhichGraphStats::ViewSamples => {
                     let mut neighs = self.view.clone();
                     neighs.extend(self.sample_view.iter().filter(|(_, x)| x.is_some())
                                   .map(|(_, x)| x.unwrap()));
-                    ByzConnGraph::peer_new(self.params.n_byzantine, self.my_id, neighs)
+                    ByzConnGraph::peer_new(net.roles(), &self.params.graph, self.my_id, net.rng().gen(), neighs)
                 },
             };
 
@@ -412,6 +481,10 @@ impl App for Brahms {
                 n_byzantine_received: self.n_byzantine_received,
                 n_byzantine_neighbors: nbn,
                 n_isolated: if nbn == self.view.len() { 1 } else { 0 },
+                byzantine_neighbors_hist: histogram_of(nbn),
+                n_view_entries: self.view.len(),
+                n_own_partition,
+                victims,
                 n_byzantine_samples: nbs,
                 min_byzantine_samples: Some(nbs as i64),
                 max_byzantine_samples: Some(nbs as i64),
@@ -427,8 +500,8 @@ impl App for Brahms {
 }
 
 impl RPS for Brahms {
-    fn get_samples(&mut self) -> Vec<PeerRef> {
-        std::mem::replace(&mut self.out_samples, Vec::new())
+    fn get_samples(&mut self, _rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        std::mem::take(&mut self.out_samples)
     }
     fn clear_samples(&mut self) {
         self.out_samples.clear();
@@ -1,4 +1,5 @@
 use crate::net::{App, PeerRef, Network, self};
+use crate::metrics::Metric;
 
 pub type Msg = bool;
 
@@ -22,9 +23,9 @@ impl net::Metrics for Metrics {
     fn headers() -> Vec<&'static str> {
         vec!["n_contaminated"]
     }
-    fn values(&self) -> Vec<String> {
+    fn values(&self) -> Vec<Metric> {
         vec![
-            format!("{}", self.n_contaminated),
+            Metric::int(self.n_contaminated),
         ]
     }
 }
@@ -42,18 +43,25 @@ impl App for Epidemic {
 
     fn init(&mut self, id: PeerRef, net: Net, _init: &Self::Init) {
         if id == 0 {
-            net.sample_peers(10).iter().for_each(|x| net.send(*x, true));
+            for x in net.sample_peers(10) {
+                net.send(x, true);
+            }
             self.contaminated = true;
         }
     }
 
     fn handle(&mut self, net: Net, _from: PeerRef, msg: &Self::Msg) {
         if *msg && !self.contaminated {
-            net.sample_peers(10).iter().for_each(|x| net.send(*x, true));
+            for x in net.sample_peers(10) {
+                net.send(x, true);
+            }
             self.contaminated = true;
         }
     }
 
+    fn on_timer(&mut self, _net: Net, _tag: usize) {
+    }
+
     fn metrics(&mut self, _net: Net) -> Self::Metrics {
         if self.contaminated {
             Self::Metrics{n_contaminated: 1}
@@ -1,18 +1,32 @@
-use rand::{thread_rng, Rng};
-use std::collections::HashSet;
+use rand::{Rng, RngCore};
+use std::collections::{BTreeMap, BTreeSet};
 use structopt::StructOpt;
 
-use crate::net::{App, PeerRef, Network};
+use crate::adversary::Behavior;
+use crate::net::{App, MessageSize, PeerRef, Network, HEADER_SIZE, PEER_SIZE};
 use crate::net::Metrics as NetMetrics;
+use crate::metrics::{add_histogram, histogram_of, Metric};
 use crate::rps;
 use crate::util::sample_nocopy;
+use crate::victims::{update_isolation, VictimStats};
 
+#[derive(Clone)]
 pub enum Msg {
-    SelfNotif,
     Step1(Vec<PeerRef>),
     Step2(Vec<PeerRef>),
 }
 
+impl MessageSize for Msg {
+    fn size(&self) -> usize {
+        match self {
+            Msg::Step1(peers) | Msg::Step2(peers) => HEADER_SIZE + peers.len() * PEER_SIZE,
+        }
+    }
+}
+
+/// Timer of the gossip rounds, fired every time unit
+const ROUND: usize = 0;
+
 #[derive(Default, Clone, StructOpt, Debug)]
 pub struct Init {
     /// Number of Byzantine nodes
@@ -39,12 +53,17 @@ pub struct RPS {
     counter: usize,
     is_byzantine: bool,
     view: Vec<PeerRef>,
+    isolated_at: Option<u64>,
 }
 
 pub struct Metrics {
     n_procs: usize,
     n_byzantine_neighbors: usize,
     n_isolated: usize,
+    byzantine_neighbors_hist: BTreeMap<u64, u64>,
+    n_view_entries: usize,
+    n_own_partition: usize,
+    victims: VictimStats,
 }
 
 impl NetMetrics for Metrics {
@@ -53,21 +72,32 @@ impl NetMetrics for Metrics {
             n_procs: 0,
             n_byzantine_neighbors: 0,
             n_isolated: 0,
+            byzantine_neighbors_hist: BTreeMap::new(),
+            n_view_entries: 0,
+            n_own_partition: 0,
+            victims: VictimStats::new(),
         }
     }
     fn net_combine(&mut self, other: &Self) {
         self.n_procs += other.n_procs;
         self.n_byzantine_neighbors += other.n_byzantine_neighbors;
         self.n_isolated += other.n_isolated;
+        add_histogram(&mut self.byzantine_neighbors_hist, &other.byzantine_neighbors_hist);
+        self.n_view_entries += other.n_view_entries;
+        self.n_own_partition += other.n_own_partition;
+        self.victims.combine(&other.victims);
     }
     fn headers() -> Vec<&'static str> {
-        vec!["avgByzN", "n_isolated"]
+        vec!["avgByzN", "n_isolated", "pOwnPart", "victByz", "victIsolT", "byzNHist"]
     }
-    fn values(&self) -> Vec<String> {
+    fn values(&self) -> Vec<Metric> {
         vec![
-            format!("{:.2}", 
-               (self.n_byzantine_neighbors as f32) / (self.n_procs as f32)),
-            format!("{}", self.n_isolated)
+            Metric::ratio(self.n_byzantine_neighbors, self.n_procs, 2),
+            Metric::int(self.n_isolated),
+            Metric::ratio(self.n_own_partition, self.n_view_entries, 4),
+            self.victims.byzantine_fractions(),
+            self.victims.isolation_times(),
+            Metric::Histogram(self.byzantine_neighbors_hist.clone()),
         ]
     }
 }
@@ -86,6 +116,7 @@ impl App for RPS {
             counter: 0,
             is_byzantine: false,
             view: Vec::new(),
+            isolated_at: None,
         }
     }
     
@@ -93,24 +124,26 @@ impl App for RPS {
         self.params = init.clone();
 
         self.my_id = id;
-        self.is_byzantine = id < init.n_byzantine;
-        if self.is_byzantine {
-            self.view = (0..self.params.view_size).collect();
-        } else {
-            self.view = net.sample_peers(self.params.view_size);
-        }
-        net.send(id, Msg::SelfNotif);
+        self.is_byzantine = net.role(id).is_byzantine();
+        self.view = net.sample_peers(self.params.view_size);
+        net.set_timer(1, ROUND);
     }
 
     fn handle(&mut self, net: Net, from: PeerRef, msg: &Self::Msg) {
-        let mut rng = thread_rng();
+        if self.is_byzantine && net.behavior() != Behavior::Honest {
+            // Requests are answered unless the adversary is silent
+            let answer = net.behavior() != Behavior::Silent;
+            match msg {
+                Msg::Step1(_) if answer => {
+                    let sent = net.advertised(from, self.params.view_size);
+                    net.send(from, Msg::Step2(sent));
+                },
+                _ => (),
+            }
+            return;
+        }
+
         let integrate = match msg {
-            Msg::SelfNotif => {
-                let i = rng.gen_range(0, self.view.len());
-                net.send(self.view[i], Msg::Step1(self.view.clone()));
-                net.send(self.my_id, Msg::SelfNotif);
-                None
-            },
             Msg::Step1(in_view) => {
                 net.send(from, Msg::Step2(self.view.clone()));
                 Some(in_view)
@@ -119,40 +152,67 @@ impl App for RPS {
                 Some(in_view)
             }
         };
-        if !self.is_byzantine {
-            if let Some(in_view) = integrate {
-                let mut tmp = self.view.iter().cloned().collect::<HashSet<_>>();
-                for x in in_view.iter() {
-                    tmp.insert(*x);
-                }
-                self.view = tmp.iter().cloned().collect::<Vec<_>>();
-                rng.shuffle(&mut self.view[..]);
-                while self.view.len() > self.params.view_size {
-                    self.view.pop();
+        if let Some(in_view) = integrate {
+            let mut tmp = self.view.iter().cloned().collect::<BTreeSet<_>>();
+            for x in in_view.iter() {
+                tmp.insert(*x);
+            }
+            self.view = tmp.iter().cloned().collect::<Vec<_>>();
+            net.rng().shuffle(&mut self.view[..]);
+            while self.view.len() > self.params.view_size {
+                self.view.pop();
+            }
+        }
+    }
+
+    fn on_timer(&mut self, net: Net, _tag: usize) {
+        if self.is_byzantine && net.behavior() != Behavior::Honest {
+            if net.behavior() == Behavior::Malicious {
+                for p in net.attack_targets(1) {
+                    let sent = net.advertised(p, self.params.view_size);
+                    net.send(p, Msg::Step1(sent));
                 }
             }
+        } else {
+            let i = net.rng().gen_range(0, self.view.len());
+            net.send(self.view[i], Msg::Step1(self.view.clone()));
         }
+        net.set_timer(1, ROUND);
     }
 
-    fn metrics(&mut self, _net: Net) -> Self::Metrics {
+    fn metrics(&mut self, net: Net) -> Self::Metrics {
         if self.is_byzantine {
             Self::Metrics::empty()
         } else {
-            let nbn = self.view.iter().filter(|x| **x < self.params.n_byzantine).count();
+            let nbn = self.view.iter().filter(|x| net.role(**x).is_byzantine()).count();
+            net.report_view(nbn, self.view.len());
+            let my_partition = net.partition(self.my_id);
+            let n_own_partition = self.view.iter()
+                .filter(|x| net.partition(**x) == my_partition).count();
+            let victims = if net.is_victim(self.my_id) {
+                update_isolation(&mut self.isolated_at, nbn == self.view.len(), net.time());
+                VictimStats::peer_new(self.my_id, nbn, self.view.len(), self.isolated_at)
+            } else {
+                VictimStats::new()
+            };
             Self::Metrics{
                 n_procs: 1,
                 n_byzantine_neighbors: nbn,
                 n_isolated: if nbn == self.view.len() { 1 } else { 0 },
+                byzantine_neighbors_hist: histogram_of(nbn),
+                n_view_entries: self.view.len(),
+                n_own_partition,
+                victims,
             }
         }
     }
 }
 
 impl rps::RPS for RPS {
-    fn get_samples(&mut self) -> Vec<PeerRef> {
-        self.counter = self.counter + 1;
-        if (self.counter + self.my_id) % self.params.period == 0 {
-            sample_nocopy(&mut self.view[..], self.params.count)
+    fn get_samples(&mut self, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        self.counter += 1;
+        if (self.counter + self.my_id).is_multiple_of(self.params.period) {
+            sample_nocopy(&mut self.view[..], self.params.count, rng)
         } else {
             vec![]
         }
@@ -1,34 +1,50 @@
-use rand::{thread_rng, Rng};
-use std::collections::{HashMap};
+use rand::{Rng, RngCore};
+use std::collections::BTreeMap;
 use structopt::StructOpt;
 
-use crate::net::{App, PeerRef, Network};
+use crate::adversary::Behavior;
+use crate::net::{App, MessageSize, PeerRef, Network, HEADER_SIZE, PEER_SIZE};
 use crate::net::Metrics as NetMetrics;
+use crate::metrics::{add_histogram, histogram_of, Metric};
 use crate::util::{either_or_if_both, sample_nocopy};
 use crate::rps::RPS;
-use crate::graph::ByzConnGraph;
+use crate::graph::{ByzConnGraph, GraphParams};
+use crate::victims::{update_isolation, VictimStats};
 
 
+#[derive(Clone)]
 pub enum Msg {
-    SelfNotif,
     Request(Vec<(PeerRef, i64)>),
     Reply(Vec<(PeerRef, i64)>),
 }
 
+impl MessageSize for Msg {
+    fn size(&self) -> usize {
+        match self {
+            // Each peer comes with an 8-byte timestamp
+            Msg::Request(peers) | Msg::Reply(peers) => HEADER_SIZE + peers.len() * (PEER_SIZE + 8),
+        }
+    }
+}
+
+/// Timer of the gossip rounds, fired every time unit
+const ROUND: usize = 0;
+
 #[derive(Clone, Default, StructOpt, Debug)]
 pub struct Init {
     /// Number of Byzantine nodes
     #[structopt(short = "t", long = "num-byzantines")]
     pub n_byzantine: usize,
 
+    /// Deprecated alias of `--adversary lurking:<time>`: Byzantine nodes
+    /// answer requests but push nothing until this time
+    #[structopt(short = "s", long = "attack-start-time")]
+    pub attack_start_time: Option<u64>,
+
     /// Byzantine flood factor
     #[structopt(short = "f", long = "byzantine-flood-factor")]
     pub byzantine_flood_factor: usize,
 
-    /// Byzantine attack start time
-    #[structopt(short = "s", long = "attack-start-time", default_value = "0")]
-    pub attack_start_time: u64,
-
     /// Replacement frequency: replace k neighbor every r (this paramter) time units
     #[structopt(short = "r", long = "sampling-frequency")]
     pub sampling_frequency: Option<u64>,
@@ -60,6 +76,9 @@ pub struct Init {
     /// Enable detailed graph statistics
     #[structopt(short = "G", long = "graph-stats")]
     pub graph_stats: bool,
+
+    #[structopt(flatten)]
+    pub graph: GraphParams,
 }
 
 pub struct SPS {
@@ -68,9 +87,9 @@ pub struct SPS {
     my_id: PeerRef,
     is_byzantine: bool,
 
-    view: HashMap<PeerRef, i64>,         // i64: ts
-    ptable: HashMap<PeerRef, PEntry>,
-    wlist: HashMap<PeerRef, i64>,       // i64: ts
+    view: BTreeMap<PeerRef, i64>,         // i64: ts
+    ptable: BTreeMap<PeerRef, PEntry>,
+    wlist: BTreeMap<PeerRef, i64>,       // i64: ts
     done: bool,
     check: PeerRef,
     request_set: Vec<PeerRef>,
@@ -81,6 +100,8 @@ pub struct SPS {
 
     n_received: usize,
     n_byzantine_received: usize,
+
+    isolated_at: Option<u64>,
 }
 
 struct PEntry {
@@ -99,6 +120,12 @@ pub struct Metrics {
     min_byzantine_neighbors: Option<i64>,
     max_byzantine_neighbors: Option<i64>,
     n_isolated: usize,
+    byzantine_neighbors_hist: BTreeMap<u64, u64>,
+
+    n_view_entries: usize,
+    n_own_partition: usize,
+
+    victims: VictimStats,
 
     graph: ByzConnGraph,
 }
@@ -113,6 +140,10 @@ impl NetMetrics for Metrics {
             min_byzantine_neighbors: None,
             max_byzantine_neighbors: None,
             n_isolated: 0,
+            byzantine_neighbors_hist: BTreeMap::new(),
+            n_view_entries: 0,
+            n_own_partition: 0,
+            victims: VictimStats::new(),
             graph: ByzConnGraph::new(),
         }
     }
@@ -132,6 +163,11 @@ impl NetMetrics for Metrics {
             &other.min_byzantine_neighbors,
             |a, b| std::cmp::min(*a, *b));
         self.n_isolated += other.n_isolated;
+        add_histogram(&mut self.byzantine_neighbors_hist, &other.byzantine_neighbors_hist);
+
+        self.n_view_entries += other.n_view_entries;
+        self.n_own_partition += other.n_own_partition;
+        self.victims.combine(&other.victims);
 
         self.graph.combine(&other.graph);
     }
@@ -144,43 +180,60 @@ impl NetMetrics for Metrics {
             "min",
             "max",
             "n_isolated",
+            "pOwnPart",
+            "victByz",
+            "victIsolT",
             "cluscoeff",
             "MPL",
+            "pUnreach",
+            "diam",
+            "n_wcc", "n_scc", "maxWcc", "maxScc", "n_unreach",
             "id_min", "id_d1", "id_q1", "id_med", "id_q3", "id_d9", "id_max",
+            "byzNHist",
         ]
     }
-    fn values(&self) -> Vec<String> {
+    fn values(&self) -> Vec<Metric> {
         // Clustering coefficient
         let cluscoeff = self.graph.clustering_coeff();
 
         // In-degree quartiles (for correct nodes)
-        let ind = self.graph.indegree_dist(self.n_procs);
+        let ind = self.graph.indegree_dist();
+
+        // Average path length, diameter estimation and unreachable pairs
+        let (mpl, diameter, unreachable) = self.graph.path_lengths();
 
-        // Average path length estimation
-        let mpl = self.graph.mean_path_length(self.n_procs);
+        // Components of the correct nodes
+        let comp = self.graph.components();
 
         vec![
-            format!("{:.2}",
-                   (self.n_received as f32) / (self.n_procs as f32)),
-            format!("{:.2}",
-                   (self.n_byzantine_received as f32) / (self.n_procs as f32)),
-            format!("{:.4}",
-                   (self.n_byzantine_received as f32) / (self.n_received as f32)),
-            format!("{:.2}",
-                   (self.n_byzantine_neighbors as f32) / (self.n_procs as f32)),
-            format!("{}", self.min_byzantine_neighbors.unwrap_or(-1)),
-            format!("{}", self.max_byzantine_neighbors.unwrap_or(-1)),
-            format!("{}", self.n_isolated),
-
-            format!("{:.4}", cluscoeff),
-            format!("{:.4}", mpl),
-            format!("{}", ind[0]),
-            format!("{}", ind[ind.len()/10]),
-            format!("{}", ind[ind.len()/4]),
-            format!("{}", ind[ind.len()/2]),
-            format!("{}", ind[3*ind.len()/4]),
-            format!("{}", ind[9*ind.len()/10]),
-            format!("{}", ind[ind.len()-1]),
+            Metric::ratio(self.n_received, self.n_procs, 2),
+            Metric::ratio(self.n_byzantine_received, self.n_procs, 2),
+            Metric::ratio(self.n_byzantine_received, self.n_received, 4),
+            Metric::ratio(self.n_byzantine_neighbors, self.n_procs, 2),
+            Metric::optional(self.min_byzantine_neighbors.map(Metric::Int)),
+            Metric::optional(self.max_byzantine_neighbors.map(Metric::Int)),
+            Metric::int(self.n_isolated),
+            Metric::ratio(self.n_own_partition, self.n_view_entries, 4),
+            self.victims.byzantine_fractions(),
+            self.victims.isolation_times(),
+
+            Metric::Float(cluscoeff, 4),
+            Metric::Float(mpl, 4),
+            Metric::Float(unreachable, 4),
+            Metric::int(diameter),
+            Metric::int(comp.n_weak),
+            Metric::int(comp.n_strong),
+            Metric::int(comp.largest_weak),
+            Metric::int(comp.largest_strong),
+            Metric::int(comp.n_unreachable),
+            Metric::int(ind[0]),
+            Metric::int(ind[ind.len()/10]),
+            Metric::int(ind[ind.len()/4]),
+            Metric::int(ind[ind.len()/2]),
+            Metric::int(ind[3*ind.len()/4]),
+            Metric::int(ind[9*ind.len()/10]),
+            Metric::int(ind[ind.len()-1]),
+            Metric::Histogram(self.byzantine_neighbors_hist.clone()),
         ]
     }
 }
@@ -211,7 +264,7 @@ impl SPS {
         for (peer, ts) in view.iter() {
             self.view.insert(*peer, *ts);
         }
-        self.view = keep_most_recent(std::mem::replace(&mut self.view, HashMap::new()),
+        self.view = keep_most_recent(std::mem::take(&mut self.view),
                                           self.params.view_size);
     }
 
@@ -262,18 +315,18 @@ impl SPS {
 
 
     fn limit_wlist(&mut self) {
-        self.wlist = keep_most_recent(std::mem::replace(&mut self.wlist, HashMap::new()),
+        self.wlist = keep_most_recent(std::mem::take(&mut self.wlist),
                                            self.params.wlist_max);
     }
 }
 
-fn keep_most_recent(mut x: HashMap<PeerRef, i64>, count: usize) -> HashMap<PeerRef, i64> {
-    let mut all_view = x.drain().collect::<Vec<(PeerRef, i64)>>();
+fn keep_most_recent(x: BTreeMap<PeerRef, i64>, count: usize) -> BTreeMap<PeerRef, i64> {
+    let mut all_view = x.into_iter().collect::<Vec<(PeerRef, i64)>>();
     all_view.sort_by_key(|(_, ts)| -ts);
     while all_view.len() > count{
         all_view.pop();
     }
-    return all_view.drain(..).collect::<HashMap<PeerRef, i64>>();
+    all_view.drain(..).collect::<BTreeMap<PeerRef, i64>>()
 }
 
 impl App for SPS {
@@ -288,9 +341,9 @@ impl App for SPS {
             my_id: 0,
             is_byzantine: false,
 
-            view: HashMap::new(),
-            ptable: HashMap::new(),
-            wlist: HashMap::new(),
+            view: BTreeMap::new(),
+            ptable: BTreeMap::new(),
+            wlist: BTreeMap::new(),
             done: false,
             check: 0,
             request_set: Vec::new(),
@@ -301,6 +354,8 @@ impl App for SPS {
 
             n_received: 0,
             n_byzantine_received: 0,
+
+            isolated_at: None,
         }
     }
     
@@ -308,110 +363,38 @@ impl App for SPS {
         self.my_id = id;
         self.params = init.clone();
 
-        self.is_byzantine = id < init.n_byzantine;
-        if !self.is_byzantine {
-            for p in net.sample_peers(self.params.view_size) {
-                self.view.insert(p, 0);
-            }
+        self.is_byzantine = net.role(id).is_byzantine();
+        for p in net.sample_peers(self.params.view_size) {
+            self.view.insert(p, 0);
         }
-        net.send(id, Msg::SelfNotif);
+        net.set_timer(1, ROUND);
     }
 
     fn handle(&mut self, net: Net, from: PeerRef, msg: &Self::Msg) {
-        if self.is_byzantine {
-            let mut byzantines = (0..self.params.n_byzantine).collect::<Vec<_>>();
+        if self.is_byzantine && net.behavior() != Behavior::Honest {
             match msg {
-                Msg::SelfNotif => {
-                    if net.time() >= self.params.attack_start_time {
-                        for p in net.sample_peers(self.params.byzantine_flood_factor) {
-                            let sent_view = sample_nocopy(&mut byzantines[..], self.params.view_size)
-                                .iter()
-                                .map(|x| (*x, net.time() as i64))
-                                .collect::<Vec<_>>();
-                            net.send(p, Msg::Request(sent_view));
-                        }
-                    }
-                    net.send(self.my_id, Msg::SelfNotif);
-                },
-                Msg::Request(_) => {
-                    if net.time() >= self.params.attack_start_time {
-                        let sent_view = sample_nocopy(&mut byzantines[..], self.params.view_size)
-                            .iter()
-                            .map(|x| (*x, net.time() as i64))
-                            .collect::<Vec<_>>();
-                        net.send(from, Msg::Reply(sent_view));
+                Msg::Request(_) if net.behavior() != Behavior::Silent => {
+                    // Before the attack, requests are answered with random peers
+                    let sent = if net.behavior() == Behavior::Lurking {
+                        net.sample_peers(self.params.view_size)
                     } else {
-                        let sent_view = net.sample_peers(self.params.view_size)
-                            .iter()
-                            .map(|x| (*x, net.time() as i64))
-                            .collect::<Vec<_>>();
-                        net.send(from, Msg::Reply(sent_view));
-                    }
+                        net.advertised(from, self.params.view_size)
+                    };
+                    let sent_view = sent
+                        .iter()
+                        .map(|x| (*x, net.time() as i64))
+                        .collect::<Vec<_>>();
+                    net.send(from, Msg::Reply(sent_view));
                 },
                 _ => (),
             }
         } else {
             match msg {
-                Msg::SelfNotif => {
-                    let mut view = self.view.iter()
-                        .map(|(k, _v)| *k)
-                        .collect::<Vec<_>>();
-
-                    if (self.my_id + net.time() as usize) % self.params.exchange_interval == 0 {
-                        self.done = false;
-
-                        let mut blacklist = self.compute_blacklist();
-
-                        // Send a request to some peers
-                        self.request_set = sample_nocopy(&mut view[..], self.params.num_exchanges);
-
-                        let mut sent = self.view.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
-                        sent.push((self.my_id, net.time() as i64));
-
-                        for p in self.request_set.iter() {
-                            if !blacklist.contains(p) {
-                                net.send(*p, Msg::Request(sent.clone()));
-                            }
-                        }
-
-                        // Send a check to a blacklisted peer
-                        if blacklist.len() > 0 {
-                            self.check = sample_nocopy(&mut blacklist[..], 1)[0];
-                            net.send(self.check, Msg::Request(sent));
-                        }
-
-                        // Decrease ptable TTL values
-                        let mut new_ptable = HashMap::new();
-                        for (peer, pentry) in self.ptable.iter() {
-                            if pentry.ttl <= 1 {
-                                self.wlist.insert(*peer, pentry.ts);
-                            } else {
-                                new_ptable.insert(*peer, PEntry{
-                                    ts: pentry.ts,
-                                    ttl: pentry.ttl - 1,
-                                    hits: pentry.hits,
-                                });
-                            }
-                        }
-                        self.limit_wlist();
-                        self.ptable = new_ptable;
-                    }
-
-                    if let Some(rf) = self.params.sampling_frequency {
-                        if (self.my_id as u64 + net.time()) % rf == 0 {
-                            if self.out_samples.len() < 200 {
-                                self.out_samples.extend(sample_nocopy(&mut view[..], self.params.sampling_count));
-                            }
-                        }
-                    }
-
-                    net.send(self.my_id, Msg::SelfNotif);
-                },
                 Msg::Request(peer_list) => {
                     // stats
                     self.n_received += peer_list.len();
                     self.n_byzantine_received += peer_list.iter()
-                        .filter(|(x, _)| *x < self.params.n_byzantine)
+                        .filter(|(x, _)| net.role(*x).is_byzantine())
                         .count();
 
                     let mut sent = self.view.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
@@ -425,11 +408,10 @@ impl App for SPS {
                     // stats
                     self.n_received += peer_list.len();
                     self.n_byzantine_received += peer_list.iter()
-                        .filter(|(x, _)| *x < self.params.n_byzantine)
+                        .filter(|(x, _)| net.role(*x).is_byzantine())
                         .count();
 
-                    let mut rng = thread_rng();
-                    let toss = rng.gen_range::<f64>(0., 1.);
+                    let toss = net.rng().gen_range::<f64>(0., 1.);
                     let thresh = 1. / (self.params.num_exchanges as f64);
                     if self.request_set.contains(&from) && !self.blacklisted(from) && !self.done && toss < thresh {
                         self.done = true;
@@ -452,25 +434,103 @@ impl App for SPS {
         }
     }
 
-    fn metrics(&mut self, _net: Net) -> Self::Metrics {
+    fn on_timer(&mut self, net: Net, _tag: usize) {
+        if self.is_byzantine && net.behavior() != Behavior::Honest {
+            if net.behavior() == Behavior::Malicious {
+                for p in net.attack_targets(self.params.byzantine_flood_factor) {
+                    let sent_view = net.advertised(p, self.params.view_size)
+                        .iter()
+                        .map(|x| (*x, net.time() as i64))
+                        .collect::<Vec<_>>();
+                    net.send(p, Msg::Request(sent_view));
+                }
+            }
+        } else {
+            let mut view = self.view.keys()
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if (self.my_id + net.time() as usize).is_multiple_of(self.params.exchange_interval) {
+                self.done = false;
+
+                let mut blacklist = self.compute_blacklist();
+
+                // Send a request to some peers
+                self.request_set = sample_nocopy(&mut view[..], self.params.num_exchanges, net.rng());
+
+                let mut sent = self.view.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+                sent.push((self.my_id, net.time() as i64));
+
+                for p in self.request_set.iter() {
+                    if !blacklist.contains(p) {
+                        net.send(*p, Msg::Request(sent.clone()));
+                    }
+                }
+
+                // Send a check to a blacklisted peer
+                if !blacklist.is_empty() {
+                    self.check = sample_nocopy(&mut blacklist[..], 1, net.rng())[0];
+                    net.send(self.check, Msg::Request(sent));
+                }
+
+                // Decrease ptable TTL values
+                let mut new_ptable = BTreeMap::new();
+                for (peer, pentry) in self.ptable.iter() {
+                    if pentry.ttl <= 1 {
+                        self.wlist.insert(*peer, pentry.ts);
+                    } else {
+                        new_ptable.insert(*peer, PEntry{
+                            ts: pentry.ts,
+                            ttl: pentry.ttl - 1,
+                            hits: pentry.hits,
+                        });
+                    }
+                }
+                self.limit_wlist();
+                self.ptable = new_ptable;
+            }
+
+            if let Some(rf) = self.params.sampling_frequency {
+                if (self.my_id as u64 + net.time()).is_multiple_of(rf) && self.out_samples.len() < 200 {
+                    self.out_samples.extend(sample_nocopy(&mut view[..], self.params.sampling_count, net.rng()));
+                }
+            }
+        }
+        net.set_timer(1, ROUND);
+    }
+
+    fn metrics(&mut self, net: Net) -> Self::Metrics {
         if self.is_byzantine {
             let mut metrics = Self::Metrics::empty();
 
             if self.params.graph_stats {
-                let neighs = (0..self.params.n_byzantine).collect::<Vec<_>>();
-                metrics.graph = ByzConnGraph::peer_new(self.params.n_byzantine,
+                let neighs = net.roles().byzantine();
+                metrics.graph = ByzConnGraph::peer_new(net.roles(),
+                                                       &self.params.graph,
                                                        self.my_id,
+                                                       net.rng().gen(),
                                                        neighs);
             }
 
             metrics
         } else {
             let nbn = self.view.iter()
-                .filter(|(entry, _)| **entry < self.params.n_byzantine).count();
+                .filter(|(entry, _)| net.role(**entry).is_byzantine()).count();
+            net.report_view(nbn, self.view.len());
+            let my_partition = net.partition(self.my_id);
+            let n_own_partition = self.view.keys()
+                .filter(|peer| net.partition(**peer) == my_partition).count();
+
+            let victims = if net.is_victim(self.my_id) {
+                update_isolation(&mut self.isolated_at, nbn == self.view.len(), net.time());
+                VictimStats::peer_new(self.my_id, nbn, self.view.len(), self.isolated_at)
+            } else {
+                VictimStats::new()
+            };
 
             let graph = if self.params.graph_stats {
-                let neighs = self.view.iter().map(|(x, _)| *x).collect::<Vec<_>>();
-                ByzConnGraph::peer_new(self.params.n_byzantine, self.my_id, neighs)
+                let neighs = self.view.keys().cloned().collect::<Vec<_>>();
+                ByzConnGraph::peer_new(net.roles(), &self.params.graph, self.my_id, net.rng().gen(), neighs)
             } else {
                 ByzConnGraph::new()
             };
@@ -481,6 +541,10 @@ impl App for SPS {
                 n_byzantine_received: self.n_byzantine_received,
                 n_byzantine_neighbors: nbn,
                 n_isolated: if nbn == self.view.len() { 1 } else { 0 },
+                byzantine_neighbors_hist: histogram_of(nbn),
+                n_view_entries: self.view.len(),
+                n_own_partition,
+                victims,
                 min_byzantine_neighbors: Some(nbn as i64),
                 max_byzantine_neighbors: Some(nbn as i64),
                 graph,
@@ -493,8 +557,8 @@ impl App for SPS {
 }
 
 impl RPS for SPS {
-    fn get_samples(&mut self) -> Vec<PeerRef> {
-        std::mem::replace(&mut self.out_samples, Vec::new())
+    fn get_samples(&mut self, _rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        std::mem::take(&mut self.out_samples)
     }
     fn clear_samples(&mut self) {
         self.out_samples.clear();
@@ -0,0 +1,143 @@
+/// Capacity of the upload or download link of every node, per time unit
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Capacity {
+    /// `none`
+    #[default]
+    Unlimited,
+    /// `msgs:<n>`, n messages per time unit
+    Messages(usize),
+    /// `bytes:<n>`, n bytes per time unit, message sizes being given by `MessageSize`
+    Bytes(usize),
+}
+
+impl std::str::FromStr for Capacity {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = s.split(':').collect::<Vec<_>>();
+        let cap = || match args.get(1).and_then(|x| x.parse::<usize>().ok()) {
+            Some(n) if n > 0 => Ok(n),
+            _ => Err("invalid link capacity"),
+        };
+        match args[0] {
+            "none" => Ok(Self::Unlimited),
+            "msgs" => Ok(Self::Messages(cap()?)),
+            "bytes" => Ok(Self::Bytes(cap()?)),
+            _ => Err("invalid link capacity (expected none, msgs:<n> or bytes:<n>)"),
+        }
+    }
+}
+
+/// What happens to messages that exceed the capacity of a link
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overflow {
+    /// `queue`, wait for the next time units in which the link has capacity left
+    #[default]
+    Queue,
+    /// `drop`, lose the message
+    Drop,
+}
+
+impl std::str::FromStr for Overflow {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(Self::Queue),
+            "drop" => Ok(Self::Drop),
+            _ => Err("invalid overflow policy (expected queue or drop)"),
+        }
+    }
+}
+
+/// Usage of one link of a node: messages go through it in FIFO order,
+/// `used` units of capacity being taken in time unit `slot`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Link {
+    slot: u64,
+    used: usize,
+}
+
+impl Link {
+    /// Time at which a message of `size` bytes offered at time `time` is
+    /// through the link, or `None` if it is dropped
+    pub fn admit(&mut self, capacity: Capacity, overflow: Overflow, time: u64, size: usize) -> Option<u64> {
+        let (cap, units) = match capacity {
+            Capacity::Unlimited => return Some(time),
+            Capacity::Messages(n) => (n, 1),
+            Capacity::Bytes(n) => (n, size),
+        };
+        if self.slot < time {
+            self.slot = time;
+            self.used = 0;
+        }
+        if overflow == Overflow::Drop && self.slot > time {
+            return None;
+        }
+        if self.used > 0 && self.used + units > cap {
+            if overflow == Overflow::Drop {
+                return None;
+            }
+            self.slot += 1;
+            self.used = 0;
+        }
+        self.used += units;
+        // Messages larger than the capacity take several time units
+        if self.used > cap {
+            self.slot += ((self.used - 1) / cap) as u64;
+            self.used = (self.used - 1) % cap + 1;
+        }
+        Some(self.slot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited() {
+        let mut link = Link::default();
+        for _ in 0..100 {
+            assert_eq!(link.admit(Capacity::Unlimited, Overflow::Drop, 3, 1000), Some(3));
+        }
+    }
+
+    #[test]
+    fn queue() {
+        let mut link = Link::default();
+        let cap = Capacity::Messages(2);
+        let times = (0..5).map(|_| link.admit(cap, Overflow::Queue, 0, 10)).collect::<Vec<_>>();
+        assert_eq!(times, vec![Some(0), Some(0), Some(1), Some(1), Some(2)]);
+        // The queue is still there at time 1, and gone at time 3
+        assert_eq!(link.admit(cap, Overflow::Queue, 1, 10), Some(2));
+        assert_eq!(link.admit(cap, Overflow::Queue, 3, 10), Some(3));
+    }
+
+    #[test]
+    fn drop() {
+        let mut link = Link::default();
+        let cap = Capacity::Bytes(10);
+        assert_eq!(link.admit(cap, Overflow::Drop, 0, 6), Some(0));
+        assert_eq!(link.admit(cap, Overflow::Drop, 0, 6), None);
+        assert_eq!(link.admit(cap, Overflow::Drop, 0, 4), Some(0));
+        assert_eq!(link.admit(cap, Overflow::Drop, 0, 1), None);
+        assert_eq!(link.admit(cap, Overflow::Drop, 1, 10), Some(1));
+    }
+
+    #[test]
+    fn oversize() {
+        let mut link = Link::default();
+        let cap = Capacity::Bytes(10);
+        // 25 bytes take time units 0, 1 and half of 2
+        assert_eq!(link.admit(cap, Overflow::Queue, 0, 25), Some(2));
+        assert_eq!(link.admit(cap, Overflow::Queue, 0, 5), Some(2));
+        assert_eq!(link.admit(cap, Overflow::Queue, 0, 1), Some(3));
+
+        // An oversized message is accepted on an idle link, but keeps it busy
+        let mut link = Link::default();
+        assert_eq!(link.admit(cap, Overflow::Drop, 0, 25), Some(2));
+        assert_eq!(link.admit(cap, Overflow::Drop, 1, 1), None);
+        assert_eq!(link.admit(cap, Overflow::Drop, 3, 1), Some(3));
+    }
+}
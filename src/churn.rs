@@ -0,0 +1,252 @@
+use rand::Rng;
+use rand::distributions::{Exp, Pareto, Poisson};
+
+use super::net::PeerRef;
+use super::roles::RoleTable;
+use super::util::{hash, seeded_rng, SimRng};
+
+/// Churn only affects correct nodes, except in trace files which can make
+/// any node join or leave. A node that leaves loses its state; it may come
+/// back later under the same identifier, in which case it restarts from
+/// scratch and bootstraps from the nodes online at that time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum WhichChurn {
+    /// `none`
+    #[default]
+    NoChurn,
+    /// `poisson:<joins per step>:<leaves per step>`
+    Poisson(f64, f64),
+    /// `session:exp:<mean session>:<mean downtime>` or
+    /// `session:pareto:<shape>:<mean session>:<mean downtime>`
+    Session(SessionDist, f64, f64),
+    /// `trace:<file>`, lines of `<time> <node> join|leave`
+    Trace(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionDist {
+    Exponential,
+    Pareto(f64),
+}
+
+impl std::str::FromStr for WhichChurn {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = s.split(':').collect::<Vec<_>>();
+        let float = |i: usize| match args.get(i).and_then(|x| x.parse::<f64>().ok()) {
+            Some(x) if x > 0. => Ok(x),
+            _ => Err("invalid churn model parameter"),
+        };
+        // A rate of 0 disables joins or leaves
+        let rate = |i: usize| match args.get(i).and_then(|x| x.parse::<f64>().ok()) {
+            Some(x) if x >= 0. => Ok(x),
+            _ => Err("invalid churn rate"),
+        };
+        match (args[0], args.get(1)) {
+            ("none", _) => Ok(Self::NoChurn),
+            ("poisson", _) => Ok(Self::Poisson(rate(1)?, rate(2)?)),
+            ("session", Some(&"exp")) => Ok(Self::Session(SessionDist::Exponential, float(2)?, float(3)?)),
+            ("session", Some(&"pareto")) => {
+                let shape = float(2)?;
+                if shape <= 1. {
+                    return Err("pareto session shape must be greater than 1");
+                }
+                Ok(Self::Session(SessionDist::Pareto(shape), float(3)?, float(4)?))
+            }
+            ("trace", Some(_)) => Ok(Self::Trace(args[1..].join(":"))),
+            _ => Err("invalid churn model"),
+        }
+    }
+}
+
+/// Times at which Byzantine nodes join the network
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum SybilArrival {
+    /// `none`, all Byzantine nodes are present from the start
+    #[default]
+    Immediate,
+    /// `linear:<start>:<end>`, Byzantine nodes join at a constant rate between the two times
+    Linear(u64, u64),
+    /// `exp:<start>:<end>`, the number of Byzantine nodes grows exponentially,
+    /// from one at the first time to all of them at the second
+    Exponential(u64, u64),
+}
+
+impl std::str::FromStr for SybilArrival {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = s.split(':').collect::<Vec<_>>();
+        let times = || match (args.get(1).and_then(|x| x.parse::<u64>().ok()),
+                              args.get(2).and_then(|x| x.parse::<u64>().ok())) {
+            (Some(start), Some(end)) if start <= end => Ok((start, end)),
+            _ => Err("invalid Sybil arrival times"),
+        };
+        match args[0] {
+            "none" => Ok(Self::Immediate),
+            "linear" => times().map(|(start, end)| Self::Linear(start, end)),
+            "exp" => times().map(|(start, end)| Self::Exponential(start, end)),
+            _ => Err("invalid Sybil arrival model"),
+        }
+    }
+}
+
+impl SybilArrival {
+    /// Arrival time of each of `n` Byzantine nodes
+    fn times(&self, n: usize) -> Vec<u64> {
+        (1..=n).map(|k| match self {
+            Self::Immediate => 0,
+            Self::Linear(start, end) => start + (end - start) * k as u64 / n as u64,
+            Self::Exponential(start, end) => {
+                let growth = if n > 1 { (k as f64).ln() / (n as f64).ln() } else { 1. };
+                start + ((end - start) as f64 * growth) as u64
+            }
+        }).collect()
+    }
+}
+
+impl SessionDist {
+    fn sample(&self, mean: f64, rng: &mut SimRng) -> u64 {
+        let d = match self {
+            Self::Exponential => rng.sample(Exp::new(1. / mean)),
+            Self::Pareto(shape) => rng.sample(Pareto::new(mean * (shape - 1.) / shape, *shape)),
+        };
+        std::cmp::max(1, d.round() as u64)
+    }
+}
+
+pub struct Churn {
+    model: WhichChurn,
+    correct: Vec<PeerRef>,
+    rng: SimRng,
+    next_transition: Vec<u64>,
+    trace: Vec<(u64, PeerRef, bool)>,
+    trace_pos: usize,
+    arrivals: Vec<(u64, PeerRef)>,
+    arrival_pos: usize,
+}
+
+impl Churn {
+    pub fn new(model: &WhichChurn, arrival: &SybilArrival, nproc: usize, roles: &RoleTable, seed: u64) -> Self {
+        let trace = match model {
+            WhichChurn::Trace(file) => load_trace(file, nproc),
+            _ => vec![],
+        };
+        let byzantine = roles.byzantine();
+        let arrivals = arrival.times(byzantine.len()).into_iter()
+            .zip(byzantine)
+            .filter(|(time, _)| *time > 0)
+            .collect();
+        Self{
+            model: model.clone(),
+            correct: roles.correct(),
+            rng: seeded_rng(hash(seed, nproc + 1), 0),
+            next_transition: vec![0; nproc],
+            trace,
+            trace_pos: 0,
+            arrivals,
+            arrival_pos: 0,
+        }
+    }
+
+    /// Byzantine nodes that are not present at the start
+    pub fn late_arrivals(&self) -> Vec<PeerRef> {
+        self.arrivals.iter().map(|(_, node)| *node).collect()
+    }
+
+    /// Nodes that join (true) or leave (false) at time `time`
+    pub fn transitions(&mut self, time: u64, online: &[bool]) -> Vec<(PeerRef, bool)> {
+        let mut ret = vec![];
+        while self.arrival_pos < self.arrivals.len() && self.arrivals[self.arrival_pos].0 <= time {
+            let node = self.arrivals[self.arrival_pos].1;
+            self.arrival_pos += 1;
+            if !online[node] {
+                ret.push((node, true));
+            }
+        }
+        ret.extend(self.churn_transitions(time, online));
+        ret
+    }
+
+    /// Number of events at the given rate, drawn only if the rate is positive
+    fn poisson(&mut self, rate: f64) -> usize {
+        if rate > 0. {
+            self.rng.sample(Poisson::new(rate)) as usize
+        } else {
+            0
+        }
+    }
+
+    fn churn_transitions(&mut self, time: u64, online: &[bool]) -> Vec<(PeerRef, bool)> {
+        match self.model.clone() {
+            WhichChurn::NoChurn => vec![],
+            WhichChurn::Poisson(join_rate, leave_rate) => {
+                let (mut up, mut down): (Vec<_>, Vec<_>) = self.correct.iter()
+                    .cloned()
+                    .partition(|i| online[*i]);
+                let n_join = self.poisson(join_rate);
+                let n_leave = self.poisson(leave_rate);
+                let mut ret = vec![];
+                for _ in 0..std::cmp::min(n_join, down.len()) {
+                    let i = self.rng.gen_range(0, down.len());
+                    ret.push((down.swap_remove(i), true));
+                }
+                for _ in 0..std::cmp::min(n_leave, up.len()) {
+                    let i = self.rng.gen_range(0, up.len());
+                    ret.push((up.swap_remove(i), false));
+                }
+                ret
+            }
+            WhichChurn::Session(dist, mean_up, mean_down) => {
+                let mut ret = vec![];
+                for i in self.correct.iter().cloned() {
+                    let is_online = online[i];
+                    if time == 0 {
+                        let mean = if is_online { mean_up } else { mean_down };
+                        self.next_transition[i] = dist.sample(mean, &mut self.rng);
+                    } else if self.next_transition[i] <= time {
+                        let mean = if is_online { mean_down } else { mean_up };
+                        self.next_transition[i] = time + dist.sample(mean, &mut self.rng);
+                        ret.push((i, !is_online));
+                    }
+                }
+                ret
+            }
+            WhichChurn::Trace(_) => {
+                let mut ret = vec![];
+                while self.trace_pos < self.trace.len() && self.trace[self.trace_pos].0 <= time {
+                    let (_, node, join) = self.trace[self.trace_pos];
+                    self.trace_pos += 1;
+                    if online[node] != join {
+                        ret.push((node, join));
+                    }
+                }
+                ret
+            }
+        }
+    }
+}
+
+fn load_trace(file: &str, nproc: usize) -> Vec<(u64, PeerRef, bool)> {
+    let contents = std::fs::read_to_string(file)
+        .unwrap_or_else(|e| panic!("cannot read churn trace {}: {}", file, e));
+    let mut trace = contents.lines()
+        .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let fields = l.split_whitespace().collect::<Vec<_>>();
+            assert!(fields.len() == 3, "invalid churn trace line: {}", l);
+            let time = fields[0].parse::<u64>().expect("invalid time in churn trace");
+            let node = fields[1].parse::<PeerRef>().expect("invalid node in churn trace");
+            assert!(node < nproc, "node {} in churn trace does not exist", node);
+            let join = match fields[2] {
+                "join" => true,
+                "leave" => false,
+                _ => panic!("invalid event in churn trace: {}", fields[2]),
+            };
+            (time, node, join)
+        })
+        .collect::<Vec<_>>();
+    trace.sort_by_key(|(time, _, _)| *time);
+    trace
+}
@@ -0,0 +1,226 @@
+use std::fs;
+
+use structopt::clap::{App, ArgMatches, ArgSettings};
+use toml::value::{Table, Value};
+
+/// Command-line arguments with those of the `--config <file>` option, if any,
+/// inserted before them. The file (TOML, or JSON if its name ends with
+/// `.json`) gives options by long name, flags as booleans, repeated options
+/// as arrays, and the subcommand as a table named after it, e.g.
+/// `[avalanche.basalt]`. Options given on the command line after the name of
+/// a subcommand of the file apply to that subcommand, and override the file;
+/// a repeated option given on the command line by its long name replaces all
+/// the values of the file. Flags, such as those set by the file, are turned
+/// off with `--no-<flag>` or `--<flag>=false`.
+pub fn args(cli: Vec<String>) -> Vec<String> {
+    let path = cli.iter().enumerate().find_map(|(i, x)| match x.strip_prefix("--config") {
+        Some("") => Some(cli.get(i + 1).expect("missing config file").clone()),
+        Some(path) => path.strip_prefix('=').map(|x| x.to_string()),
+        None => None,
+    });
+    let table = path.map(|path| load(&path)).unwrap_or_default();
+    let mut ret = vec![cli[0].clone()];
+    ret.extend(merge(&table, &cli[1..]));
+    ret
+}
+
+fn load(path: &str) -> Table {
+    let text = fs::read_to_string(path).expect("cannot read config file");
+    if path.ends_with(".json") {
+        match from_json(serde_json::from_str(&text).expect("invalid JSON config file")) {
+            Some(Value::Table(table)) => table,
+            _ => panic!("JSON config file must be an object"),
+        }
+    } else {
+        toml::from_str(&text).expect("invalid TOML config file")
+    }
+}
+
+fn from_json(v: serde_json::Value) -> Option<Value> {
+    use serde_json::Value as Json;
+    Some(match v {
+        Json::Null => return None,
+        Json::Bool(b) => Value::Boolean(b),
+        Json::Number(x) => match x.as_i64() {
+            Some(x) => Value::Integer(x),
+            None => Value::Float(x.as_f64().unwrap()),
+        },
+        Json::String(s) => Value::String(s),
+        Json::Array(values) => Value::Array(values.into_iter().filter_map(from_json).collect()),
+        Json::Object(fields) => Value::Table(fields.into_iter()
+            .filter_map(|(k, v)| from_json(v).map(|v| (k, v)))
+            .collect()),
+    })
+}
+
+/// Arguments of the options of `table`, followed by those of `cli` given
+/// before the subcommand of `table`, then by the subcommand
+fn merge(table: &Table, cli: &[String]) -> Vec<String> {
+    let scalar = |key: &str, v: &Value| match v {
+        Value::String(s) => format!("--{}={}", key, s),
+        Value::Integer(x) => format!("--{}={}", key, x),
+        Value::Float(x) => format!("--{}={}", key, x),
+        _ => panic!("invalid value of config option {}", key),
+    };
+    let mut subs = table.iter().filter_map(|(key, value)| match value {
+        Value::Table(t) => Some((key, t)),
+        _ => None,
+    });
+    let sub = subs.next();
+    assert!(subs.next().is_none(), "config file has several subcommands");
+    // Arguments of the command line that apply at this level
+    let pos = sub.and_then(|(name, _)| cli.iter().position(|x| x == name)).unwrap_or(cli.len());
+    let given = |key: &str| {
+        let option = format!("--{}", key);
+        cli[..pos].iter().any(|x| *x == option || x.starts_with(&format!("{}=", option)))
+    };
+    let disabled = |key: &str| cli[..pos].iter().any(|x| flag(x) == Some((key, false)));
+
+    let mut ret = vec![];
+    for (key, value) in table.iter() {
+        match value {
+            Value::Table(_) => (),
+            Value::Boolean(true) if disabled(key) => (),
+            Value::Boolean(true) => ret.push(format!("--{}", key)),
+            Value::Boolean(false) => (),
+            Value::Array(_) if given(key) => (),
+            Value::Array(values) => ret.extend(values.iter().map(|v| scalar(key, v))),
+            v => ret.push(scalar(key, v)),
+        }
+    }
+    // clap only knows flags by their name
+    ret.extend(cli[..pos].iter().filter_map(|x| match flag(x) {
+        Some((key, true)) => Some(format!("--{}", key)),
+        Some((_, false)) => None,
+        None => Some(x.clone()),
+    }));
+    if let Some((name, t)) = sub {
+        ret.push(name.clone());
+        ret.extend(merge(t, cli.get(pos + 1..).unwrap_or(&[])));
+    }
+    ret
+}
+
+/// Flag and value of `--no-<flag>`, `--<flag>=false` or `--<flag>=true`
+fn flag(arg: &str) -> Option<(&str, bool)> {
+    let arg = arg.strip_prefix("--")?;
+    match arg.split_once('=') {
+        Some((key, "true")) => Some((key, true)),
+        Some((key, "false")) => Some((key, false)),
+        Some(_) => None,
+        None => arg.strip_prefix("no-").map(|key| (key, false)),
+    }
+}
+
+/// Effective configuration of the command line `matches` parsed by `app`,
+/// default values included, in the format read by `args`
+pub fn dump(app: &App, matches: &ArgMatches) -> Table {
+    // clap 2 has no public way of listing the arguments of an App
+    let mut ret = Table::new();
+    for flag in app.p.flags.iter() {
+        match flag.s.long {
+            Some("help") | Some("version") | None => (),
+            Some(long) => {
+                ret.insert(long.to_string(), Value::Boolean(matches.is_present(flag.b.name)));
+            }
+        }
+    }
+    for opt in app.p.opts.iter() {
+        if let (Some(long), Some(values)) = (opt.s.long, matches.values_of(opt.b.name)) {
+            let mut values = values.map(value).collect::<Vec<_>>();
+            let value = if opt.b.is_set(ArgSettings::Multiple) {
+                Value::Array(values)
+            } else {
+                values.remove(0)
+            };
+            ret.insert(long.to_string(), value);
+        }
+    }
+    if let (name, Some(sub_matches)) = matches.subcommand() {
+        let sub = app.p.subcommands.iter().find(|x| x.p.meta.name == name).unwrap();
+        ret.insert(name.to_string(), Value::Table(dump(sub, sub_matches)));
+    }
+    ret
+}
+
+fn value(s: &str) -> Value {
+    match (s.parse::<i64>(), s.parse::<f64>()) {
+        (Ok(x), _) => Value::Integer(x),
+        (_, Ok(x)) if x.is_finite() && s.contains('.') => Value::Float(x),
+        _ => Value::String(s.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn strings(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    const CONFIG: &str = r#"
+        seed = 7
+        event = ["3:partition:2", "8:heal"]
+        random-roles = true
+        [basalt]
+        view-size = 10
+        num-byzantines = 5
+    "#;
+
+    #[test]
+    fn file_then_command_line() {
+        let table = toml::from_str(CONFIG).unwrap();
+        assert_eq!(merge(&table, &strings(&["-T", "5", "basalt", "-f", "3"])), strings(&[
+            "--event=3:partition:2", "--event=8:heal", "--random-roles", "--seed=7", "-T", "5",
+            "basalt", "--num-byzantines=5", "--view-size=10", "-f", "3",
+        ]));
+    }
+
+    #[test]
+    fn merged_values_are_parsed() {
+        let table = toml::from_str(CONFIG).unwrap();
+        let mut args = strings(&["basalt-sim"]);
+        args.extend(merge(&table, &strings(&["basalt", "-f", "3", "-i", "4"])));
+        let opt = crate::Opt::from_iter(&args);
+        assert!(opt.net.random_roles);
+        assert_eq!(opt.seed, Some(7));
+        match opt.app {
+            crate::WhichApp::Basalt(pp) => {
+                assert_eq!(pp.n_byzantine, 5);
+                assert_eq!(pp.view_size, 10);
+            }
+            _ => panic!("wrong subcommand"),
+        }
+    }
+
+    #[test]
+    fn flags_of_the_file_can_be_turned_off() {
+        let table = toml::from_str(CONFIG).unwrap();
+        for off in ["--no-random-roles", "--random-roles=false"] {
+            let args = merge(&table, &strings(&[off, "basalt"]));
+            assert!(!args.iter().any(|x| x.contains("random-roles")));
+        }
+        let args = merge(&Table::new(), &strings(&["--random-roles=true", "basalt", "--no-use-hit-counter"]));
+        assert_eq!(args, strings(&["--random-roles", "basalt"]));
+        // Other options with a value are left alone
+        let args = merge(&Table::new(), &strings(&["--latency=const=false", "--nodes=10"]));
+        assert_eq!(args, strings(&["--latency=const=false", "--nodes=10"]));
+    }
+
+    #[test]
+    fn repeated_options_replace_the_file() {
+        let table = toml::from_str(CONFIG).unwrap();
+        let expected = strings(&[
+            "--random-roles", "--seed=7", "--event", "5:heal",
+            "basalt", "--num-byzantines=5", "--view-size=10",
+        ]);
+        assert_eq!(merge(&table, &strings(&["--event", "5:heal", "basalt"])), expected);
+        assert_eq!(merge(&table, &strings(&["--event=5:heal", "basalt"])).len(), expected.len() - 1);
+        // Only options given at the level of the subcommand replace its values
+        let table = toml::from_str("[sweep]\nevent = [\"1:heal\"]").unwrap();
+        assert_eq!(merge(&table, &strings(&["--event", "5:heal", "sweep"])),
+                   strings(&["--event", "5:heal", "sweep", "--event=1:heal"]));
+    }
+}
@@ -0,0 +1,35 @@
+/// How the simulator advances time within a step. Metrics, churn and
+/// scheduled events are always handled at step boundaries.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WhichEngine {
+    /// `lockstep`, each node handles at once all the messages that arrive
+    /// during the step, nodes running in parallel; messages sent during the
+    /// step are only handled at the next step, even if they arrive earlier
+    #[default]
+    Lockstep,
+    /// `event`, discrete-event engine: messages are handled one at a time
+    /// in order of arrival time, ties being broken by order of sending
+    Event,
+    /// `event:parallel`, conservative parallel discrete-event engine: the
+    /// step is cut into windows of the minimum latency (one time unit),
+    /// during which no message can be both sent and handled, and nodes run
+    /// in parallel within each window
+    ParallelEvent,
+}
+
+/// Time during which no message sent can be handled, as every latency is at
+/// least one time unit
+pub const LOOKAHEAD: u64 = 1;
+
+impl std::str::FromStr for WhichEngine {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lockstep" => Ok(Self::Lockstep),
+            "event" => Ok(Self::Event),
+            "event:parallel" => Ok(Self::ParallelEvent),
+            _ => Err("invalid engine (expected lockstep, event or event:parallel)"),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+/// A probability for each class of link, depending on whether the sender
+/// and the receiver are correct (c) or Byzantine (b).
+///
+/// Parsed either from a single probability applying to all links, or from a
+/// list of per-class values such as `cc=0.1,cb=0.2` (unspecified classes are 0).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PerLink {
+    pub cc: f64,
+    pub cb: f64,
+    pub bc: f64,
+    pub bb: f64,
+}
+
+impl PerLink {
+    pub fn get(&self, from_byzantine: bool, to_byzantine: bool) -> f64 {
+        match (from_byzantine, to_byzantine) {
+            (false, false) => self.cc,
+            (false, true) => self.cb,
+            (true, false) => self.bc,
+            (true, true) => self.bb,
+        }
+    }
+}
+
+impl std::str::FromStr for PerLink {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let prob = |x: &str| match x.parse::<f64>() {
+            Ok(p) if (0. ..=1.).contains(&p) => Ok(p),
+            _ => Err("invalid probability"),
+        };
+        if !s.contains('=') {
+            let p = prob(s)?;
+            return Ok(Self{ cc: p, cb: p, bc: p, bb: p });
+        }
+        let mut ret = Self::default();
+        for item in s.split(',') {
+            let mut kv = item.splitn(2, '=');
+            let class = kv.next().unwrap().trim();
+            let p = prob(kv.next().ok_or("invalid link class probability")?.trim())?;
+            match class {
+                "cc" => ret.cc = p,
+                "cb" => ret.cb = p,
+                "bc" => ret.bc = p,
+                "bb" => ret.bb = p,
+                _ => return Err("invalid link class (expected cc, cb, bc or bb)"),
+            }
+        }
+        Ok(ret)
+    }
+}
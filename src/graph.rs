@@ -1,28 +1,114 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
 
-use rand::{thread_rng, Rng};
 use rayon::prelude::*;
+use structopt::StructOpt;
 
 use super::net::PeerRef;
-use super::util::either_or_if_both;
+use super::roles::RoleTable;
+use super::util::{either_or_if_both, sample, seeded_rng};
 
+/// Options of the graph statistics of `-G`
+#[derive(Clone, Copy, Debug, PartialEq, StructOpt)]
+pub struct GraphParams {
+    /// Number of distinct random roots from which the mean path length and
+    /// the diameter are estimated (every correct node if there are fewer)
+    #[structopt(long = "path-samples", default_value = "32")]
+    pub path_samples: usize,
+
+    /// Compute the mean path length and the diameter from every correct node
+    #[structopt(long = "exact-paths")]
+    pub exact_paths: bool,
+
+    /// Number of random correct nodes whose clustering coefficients are
+    /// averaged (0 for all of them)
+    #[structopt(long = "clustering-samples", default_value = "0")]
+    pub clustering_samples: usize,
+
+    /// Print the time taken by graph statistics to the standard error
+    #[structopt(long = "graph-timing")]
+    pub timing: bool,
+}
+
+impl Default for GraphParams {
+    fn default() -> Self {
+        Self::from_iter(&["graph"])
+    }
+}
+
+/// Components of the subgraph of the correct nodes of a graph, without
+/// the edges to and from Byzantine nodes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Components {
+    pub n_weak: usize,
+    pub n_strong: usize,
+    pub largest_weak: usize,
+    pub largest_strong: usize,
+    /// Correct nodes that cannot be reached from the largest strongly
+    /// connected component
+    pub n_unreachable: usize,
+}
+
+#[derive(Default)]
 pub struct ByzConnGraph {
-    n_byzantine: Option<usize>,
-    graph: HashMap<PeerRef, Arc<Vec<PeerRef>>>,
+    roles: Option<Arc<RoleTable>>,
+    params: Option<GraphParams>,
+    seed: u64,
+    graph: BTreeMap<PeerRef, Arc<Vec<PeerRef>>>,
+    /// Built from `graph` the first time a statistic is computed
+    csr: OnceLock<Csr>,
+}
+
+/// Adjacency lists of a graph in compressed sparse row form, indexed by node
+/// identifier; nodes that are not in the graph have no neighbors
+struct Csr {
+    offsets: Vec<usize>,
+    targets: Vec<u32>,
+}
+
+impl Csr {
+    fn new(graph: &BTreeMap<PeerRef, Arc<Vec<PeerRef>>>) -> Self {
+        let n = graph.iter()
+            .map(|(k, v)| std::cmp::max(*k, v.last().copied().unwrap_or(0)) + 1)
+            .max()
+            .unwrap_or(0);
+        assert!(n <= u32::MAX as usize, "too many nodes for graph statistics");
+        let mut offsets = Vec::with_capacity(n + 1);
+        let mut targets = Vec::with_capacity(graph.values().map(|v| v.len()).sum());
+        offsets.push(0);
+        for (k, v) in graph.iter() {
+            offsets.resize(k + 1, targets.len());
+            targets.extend(v.iter().map(|x| *x as u32));
+            offsets.push(targets.len());
+        }
+        offsets.resize(n + 1, targets.len());
+        Self{ offsets, targets }
+    }
+
+    fn n_nodes(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Neighbors of `peer`, sorted
+    fn neighbors(&self, peer: PeerRef) -> &[u32] {
+        &self.targets[self.offsets[peer]..self.offsets[peer + 1]]
+    }
 }
 
 impl ByzConnGraph {
     pub fn new() -> Self {
-        Self{
-            n_byzantine: None,
-            graph: HashMap::new(),
-        }
+        Self::default()
     }
-    pub fn peer_new(n_byzantine: usize, peer: PeerRef, mut neighbors: Vec<PeerRef>) -> Self {
+
+    /// `seed` is drawn from the peer's RNG; contributions of all peers are combined
+    /// to pick the roots of path length estimation deterministically
+    pub fn peer_new(roles: Arc<RoleTable>, params: &GraphParams, peer: PeerRef, seed: u64, mut neighbors: Vec<PeerRef>) -> Self {
         let mut ret = Self {
-            n_byzantine: Some(n_byzantine),
-            graph: HashMap::new(),
+            roles: Some(roles),
+            params: Some(*params),
+            seed,
+            ..Self::default()
         };
         neighbors.sort();
         neighbors.dedup();
@@ -31,14 +117,43 @@ impl ByzConnGraph {
     }
 
     pub fn combine(&mut self, other: &Self) {
-        self.n_byzantine = either_or_if_both(
-            &self.n_byzantine,
-            &other.n_byzantine,
-            |a, b| { assert!(*a == *b); *a });
+        self.roles = either_or_if_both(
+            &self.roles,
+            &other.roles,
+            |a, b| { assert!(Arc::ptr_eq(a, b)); a.clone() });
+        self.params = either_or_if_both(&self.params, &other.params, |a, _| *a);
+        self.seed ^= other.seed;
 
         for (k, v) in other.graph.iter() {
             self.graph.insert(*k, v.clone());
         }
+        self.csr = OnceLock::new();
+    }
+
+    fn params(&self) -> GraphParams {
+        self.params.unwrap_or_default()
+    }
+
+    fn csr(&self) -> &Csr {
+        self.csr.get_or_init(|| self.timed("graph construction", || Csr::new(&self.graph)))
+    }
+
+    /// Runs `f`, printing its duration with `--graph-timing`
+    fn timed<T, F: FnOnce() -> T>(&self, what: &str, f: F) -> T {
+        let start = Instant::now();
+        let ret = f();
+        if self.params().timing {
+            eprintln!("{} ({} nodes): {:.3}s", what, self.graph.len(), start.elapsed().as_secs_f64());
+        }
+        ret
+    }
+
+    /// Correct nodes of the graph, by increasing identifier
+    fn correct(&self, roles: &RoleTable) -> Vec<PeerRef> {
+        self.graph.keys()
+            .filter(|x| !roles.is_byzantine(**x))
+            .cloned()
+            .collect()
     }
 
     pub fn clustering_coeff(&self) -> f64 {
@@ -46,85 +161,395 @@ impl ByzConnGraph {
             return 0.;
         }
 
-        let n_byzantine = self.n_byzantine.unwrap();
+        let roles = self.roles.as_ref().unwrap();
+        let csr = self.csr();
+        let mut nodes = self.correct(roles);
+        let n_samples = self.params().clustering_samples;
+        if n_samples > 0 && n_samples < nodes.len() {
+            nodes = sample(&nodes, n_samples, &mut seeded_rng(self.seed, 1));
+            nodes.sort();
+        }
 
-        let local_coeffs = self.graph.par_iter()
-            .filter(|(x, _)| **x >= n_byzantine)
-            .map(|(_, neighbors)| {
-            let mut links = 0;
-            for n in neighbors.iter() {
-                if let Some(neigneig) = self.graph.get(n) {
-                    for z in neigneig.iter() {
+        self.timed("clustering coefficient", || {
+            let local_coeffs = nodes.par_iter()
+                .map(|x| {
+                let neighbors = csr.neighbors(*x);
+                let mut links = 0;
+                for n in neighbors.iter() {
+                    for z in csr.neighbors(*n as PeerRef).iter() {
                         if neighbors.binary_search(z).is_ok() {
-                            links = links + 1;
+                            links += 1;
                         }
                     }
                 }
-            }
-            (links as f64) / ((neighbors.len() as f64)  * (neighbors.len() as f64 - 1.0))
-        }).collect::<Vec<_>>();
-        local_coeffs.iter().fold(0., |x, y| x + y) / local_coeffs.len() as f64
+                (links as f64) / ((neighbors.len() as f64)  * (neighbors.len() as f64 - 1.0))
+            }).collect::<Vec<_>>();
+            local_coeffs.iter().fold(0., |x, y| x + y) / local_coeffs.len() as f64
+        })
     }
 
-    pub fn indegree_dist(&self, n_procs: usize) -> Vec<usize> {
-        if self.graph.is_empty() {
-            return vec![0];
-        }
-
-        let n_byzantine = self.n_byzantine.unwrap();
+    /// In-degree of each correct node of the graph, sorted
+    pub fn indegree_dist(&self) -> Vec<usize> {
+        let roles = match &self.roles {
+            Some(roles) => roles,
+            None => return vec![0],
+        };
 
-        let mut ind = vec![0; n_procs];
-        for (_, neigh) in self.graph.iter() {
-            for i in neigh.iter() {
-                if *i >= n_byzantine {
-                    ind[i - n_byzantine] += 1;
-                }
-            }
+        let csr = self.csr();
+        let mut counts = vec![0; csr.n_nodes()];
+        for i in csr.targets.iter() {
+            counts[*i as usize] += 1;
+        }
+        let mut ind = self.correct(roles).into_iter()
+            .map(|x| counts[x])
+            .collect::<Vec<_>>();
+        if ind.is_empty() {
+            ind.push(0);
         }
         ind.sort();
         ind
     }
 
-    pub fn mean_path_length(&self, n_procs: usize) -> f64 {
-        if self.graph.is_empty() {
-            return 0.;
+    /// Mean length of the shortest paths between correct nodes, through
+    /// correct nodes, and the largest of those lengths (the diameter), from
+    /// distinct random roots or, with `--exact-paths`, from every correct node; the
+    /// mean is that of the mean distance from each root to the nodes it reaches,
+    /// so it is followed by the fraction of pairs of a root and another correct
+    /// node with no path between them
+    pub fn path_lengths(&self) -> (f64, usize, f64) {
+        let roles = match &self.roles {
+            Some(roles) => roles,
+            None => return (0., 0, 0.),
+        };
+        let correct = self.correct(roles);
+        if correct.is_empty() {
+            return (0., 0, 0.);
         }
+        let n_correct = correct.len();
 
-        let n_byzantine = self.n_byzantine.unwrap();
+        let params = self.params();
+        let roots = if params.exact_paths {
+            correct
+        } else {
+            sample(&correct, params.path_samples, &mut seeded_rng(self.seed, 0))
+        };
+        if roots.is_empty() {
+            return (0., 0, 0.);
+        }
+        let csr = self.csr();
 
-        let mut rng = thread_rng();
-        let roots = (0..32).map(|_| rng.gen_range(0, n_procs) + n_byzantine)
-            .collect::<Vec<_>>();
-        let avgdist = roots.par_iter().map(|root| {
-                let mut dmap = HashMap::new();
-                dmap.insert(*root, 0);
-                let mut prev = HashSet::new();
-                prev.insert(*root);
-                let mut bfs_dist = 1;
-                while !prev.is_empty() {
-                    let mut next = HashSet::new();
-                    for n in prev.iter() {
-                        if let Some(nnl) = self.graph.get(n) {
-                            for nn in nnl.iter() {
-                                if *nn >= n_byzantine {
-                                    if !dmap.contains_key(nn) {
-                                        next.insert(*nn);
-                                    }
+        self.timed("path lengths", || {
+            // Distances of each BFS are reset after it, so that every thread
+            // allocates them only once
+            let from_roots = roots.par_iter()
+                .map_init(|| vec![u32::MAX; csr.n_nodes()], |dist, root| {
+                    dist[*root] = 0;
+                    let mut visited = vec![*root as u32];
+                    let mut sum = 0;
+                    let mut begin = 0;
+                    let mut bfs_dist = 0;
+                    while begin < visited.len() {
+                        let end = visited.len();
+                        bfs_dist += 1;
+                        for i in begin..end {
+                            for nn in csr.neighbors(visited[i] as PeerRef).iter() {
+                                let nn = *nn as PeerRef;
+                                if !roles.is_byzantine(nn) && dist[nn] == u32::MAX {
+                                    dist[nn] = bfs_dist;
+                                    sum += bfs_dist as usize;
+                                    visited.push(nn as u32);
                                 }
                             }
-                        } else {
-                            println!("No neighbors: {}", n);
                         }
+                        begin = end;
                     }
-                    for n in next.iter() {
-                        dmap.insert(*n, bfs_dist);
+                    let eccentricity = dist[*visited.last().unwrap() as PeerRef] as usize;
+                    for n in visited.iter() {
+                        dist[*n as PeerRef] = u32::MAX;
                     }
-                    bfs_dist += 1;
-                    prev = next;
+                    (sum as f64 / visited.len() as f64, eccentricity, n_correct - visited.len())
+                })
+                .collect::<Vec<_>>();
+
+            let mean = from_roots.iter().fold(0., |x, (y, _, _)| x + y) / from_roots.len() as f64;
+            let n_unreachable = from_roots.iter().map(|(_, _, u)| *u).sum::<usize>();
+            let unreachable = match n_correct {
+                1 => 0.,
+                _ => n_unreachable as f64 / (from_roots.len() * (n_correct - 1)) as f64,
+            };
+            (mean, from_roots.iter().map(|(_, e, _)| *e).max().unwrap(), unreachable)
+        })
+    }
+
+    /// Connected components of the correct nodes of the graph, following
+    /// only edges between correct nodes
+    pub fn components(&self) -> Components {
+        let roles = match &self.roles {
+            Some(roles) => roles,
+            None => return Components::default(),
+        };
+        let nodes = self.correct(roles);
+        if nodes.is_empty() {
+            return Components::default();
+        }
+        let csr = self.csr();
+        let mut member = vec![false; csr.n_nodes()];
+        for x in nodes.iter() {
+            member[*x] = true;
+        }
+        let edges = |x: PeerRef| csr.neighbors(x).iter()
+            .map(|y| *y as PeerRef)
+            .filter(|y| member[*y]);
+
+        self.timed("components", || {
+            let mut ret = Components::default();
+
+            // Weakly connected components, by union-find
+            let mut parent = (0..csr.n_nodes()).collect::<Vec<_>>();
+            fn find(parent: &mut [PeerRef], mut x: PeerRef) -> PeerRef {
+                while parent[x] != x {
+                    parent[x] = parent[parent[x]];
+                    x = parent[x];
                 }
-                dmap.iter().fold(0, |a, (_, b)| a + b) as f64 / dmap.len() as f64
-            }).collect::<Vec<_>>();
+                x
+            }
+            for x in nodes.iter() {
+                for y in edges(*x) {
+                    let (a, b) = (find(&mut parent, *x), find(&mut parent, y));
+                    parent[std::cmp::max(a, b)] = std::cmp::min(a, b);
+                }
+            }
+            let mut sizes = BTreeMap::new();
+            for x in nodes.iter() {
+                *sizes.entry(find(&mut parent, *x)).or_insert(0) += 1;
+            }
+            ret.n_weak = sizes.len();
+            ret.largest_weak = sizes.values().copied().max().unwrap();
+
+            // Strongly connected components, by Tarjan's algorithm with an
+            // explicit call stack of (node, index of its next neighbor)
+            const NONE: usize = usize::MAX;
+            let mut index = vec![NONE; csr.n_nodes()];
+            let mut low = vec![0; csr.n_nodes()];
+            let mut on_stack = vec![false; csr.n_nodes()];
+            let mut stack = vec![];
+            let mut calls: Vec<(PeerRef, usize)> = vec![];
+            let mut next_index = 0;
+            let mut largest = (0, nodes[0]);
+            for root in nodes.iter() {
+                if index[*root] != NONE {
+                    continue;
+                }
+                index[*root] = next_index;
+                low[*root] = next_index;
+                next_index += 1;
+                stack.push(*root);
+                on_stack[*root] = true;
+                calls.push((*root, 0));
+                while let Some((x, i)) = calls.last().copied() {
+                    let neighbors = csr.neighbors(x);
+                    if i < neighbors.len() {
+                        calls.last_mut().unwrap().1 += 1;
+                        let y = neighbors[i] as PeerRef;
+                        if !member[y] {
+                            continue;
+                        }
+                        if index[y] == NONE {
+                            index[y] = next_index;
+                            low[y] = next_index;
+                            next_index += 1;
+                            stack.push(y);
+                            on_stack[y] = true;
+                            calls.push((y, 0));
+                        } else if on_stack[y] {
+                            low[x] = std::cmp::min(low[x], index[y]);
+                        }
+                        continue;
+                    }
+                    calls.pop();
+                    if let Some((parent, _)) = calls.last() {
+                        low[*parent] = std::cmp::min(low[*parent], low[x]);
+                    }
+                    if low[x] == index[x] {
+                        let mut size = 0;
+                        loop {
+                            let y = stack.pop().unwrap();
+                            on_stack[y] = false;
+                            size += 1;
+                            if y == x {
+                                break;
+                            }
+                        }
+                        ret.n_strong += 1;
+                        if size > largest.0 {
+                            largest = (size, x);
+                        }
+                    }
+                }
+            }
+            ret.largest_strong = largest.0;
+
+            // Nodes reached from the largest strongly connected component
+            let mut reached = vec![false; csr.n_nodes()];
+            reached[largest.1] = true;
+            let mut queue = vec![largest.1];
+            while let Some(x) = queue.pop() {
+                for y in edges(x) {
+                    if !reached[y] {
+                        reached[y] = true;
+                        queue.push(y);
+                    }
+                }
+            }
+            ret.n_unreachable = nodes.iter().filter(|x| !reached[**x]).count();
+            ret
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Graph of `n` nodes, the first `n_byzantine` of them Byzantine, with
+    /// the given directed edges
+    fn graph(n: usize, n_byzantine: usize, edges: &[(PeerRef, PeerRef)], params: GraphParams) -> ByzConnGraph {
+        let roles = Arc::new(RoleTable::new(n, n_byzantine, false, 0));
+        let mut ret = ByzConnGraph::new();
+        for x in 0..n {
+            let neighbors = edges.iter().filter(|(a, _)| *a == x).map(|(_, b)| *b).collect();
+            ret.combine(&ByzConnGraph::peer_new(roles.clone(), &params, x, x as u64, neighbors));
+        }
+        ret
+    }
+
+    fn undirected(edges: &[(PeerRef, PeerRef)]) -> Vec<(PeerRef, PeerRef)> {
+        edges.iter().flat_map(|(a, b)| vec![(*a, *b), (*b, *a)]).collect()
+    }
+
+    fn exact() -> GraphParams {
+        GraphParams{ exact_paths: true, ..GraphParams::default() }
+    }
+
+    #[test]
+    fn csr() {
+        let g = graph(6, 0, &[(1, 3), (1, 0), (3, 1), (3, 3)], exact());
+        let csr = g.csr();
+        assert_eq!(csr.n_nodes(), 6);
+        assert_eq!(csr.neighbors(0), &[] as &[u32]);
+        assert_eq!(csr.neighbors(1), &[0, 3]);
+        assert_eq!(csr.neighbors(3), &[1, 3]);
+        assert_eq!(csr.neighbors(5), &[] as &[u32]);
+        assert_eq!(g.indegree_dist(), vec![0, 0, 0, 1, 1, 2]);
+    }
+
+    #[test]
+    fn path_graph() {
+        let g = graph(5, 0, &undirected(&[(0, 1), (1, 2), (2, 3), (3, 4)]), exact());
+        let (mean, diameter, unreachable) = g.path_lengths();
+        // Mean distances from each node: 2, 1.4, 1.2, 1.4, 2
+        assert!((mean - 1.6).abs() < 1e-12);
+        assert_eq!(diameter, 4);
+        assert_eq!(unreachable, 0.);
+    }
+
+    #[test]
+    fn star_graph() {
+        let g = graph(5, 0, &undirected(&[(0, 1), (0, 2), (0, 3), (0, 4)]), exact());
+        let (mean, diameter, _) = g.path_lengths();
+        // Mean distances from the center: 0.8, from each leaf: 1.4
+        assert!((mean - 1.28).abs() < 1e-12);
+        assert_eq!(diameter, 2);
+    }
+
+    #[test]
+    fn byzantine_nodes_are_not_paths() {
+        // 1 - 0 - 2 with 0 Byzantine: the correct nodes reach nothing
+        let g = graph(3, 1, &undirected(&[(0, 1), (0, 2)]), exact());
+        assert_eq!(g.path_lengths(), (0., 0, 1.));
+    }
+
+    #[test]
+    fn unreachable_pairs() {
+        // 0 - 1 and 2 - 3 - 4: 0 and 1 miss 3 of the 4 other nodes, the others 2
+        let g = graph(5, 0, &undirected(&[(0, 1), (2, 3), (3, 4)]), exact());
+        let (_, diameter, unreachable) = g.path_lengths();
+        assert_eq!(diameter, 2);
+        assert!((unreachable - 12. / 20.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn sampled_paths_agree_with_exact_ones() {
+        let edges = undirected(&[(0, 1), (1, 2), (2, 3), (3, 4), (1, 5), (5, 6), (6, 2), (4, 7)]);
+        let (mean, diameter, _) = graph(8, 0, &edges, exact()).path_lengths();
+        for n in [8, 100] {
+            let params = GraphParams{ path_samples: n, ..GraphParams::default() };
+            let (sampled_mean, sampled_diameter, _) = graph(8, 0, &edges, params).path_lengths();
+            assert!((sampled_mean - mean).abs() < 1e-12);
+            assert_eq!(sampled_diameter, diameter);
+        }
+    }
+
+    fn components(n: usize, n_byzantine: usize, edges: &[(PeerRef, PeerRef)]) -> Components {
+        graph(n, n_byzantine, edges, exact()).components()
+    }
+
+    #[test]
+    fn cycle() {
+        assert_eq!(components(4, 0, &[(0, 1), (1, 2), (2, 3), (3, 0)]), Components{
+            n_weak: 1, n_strong: 1, largest_weak: 4, largest_strong: 4, n_unreachable: 0,
+        });
+    }
+
+    #[test]
+    fn dag() {
+        // Each node is its own strong component; the first one completed,
+        // the sink 3, is taken as the largest and reaches only itself
+        assert_eq!(components(4, 0, &[(0, 1), (0, 2), (1, 3), (2, 3)]), Components{
+            n_weak: 1, n_strong: 4, largest_weak: 4, largest_strong: 1, n_unreachable: 3,
+        });
+    }
+
+    #[test]
+    fn cycle_with_tails() {
+        // 4 -> 0 -> 1 -> 2 -> 0, 2 -> 3: the cycle reaches 3 but not 4
+        assert_eq!(components(5, 0, &[(4, 0), (0, 1), (1, 2), (2, 0), (2, 3)]), Components{
+            n_weak: 1, n_strong: 3, largest_weak: 5, largest_strong: 3, n_unreachable: 1,
+        });
+    }
+
+    #[test]
+    fn nested_cycles() {
+        // The back edge 4 -> 1 closes a cycle through the inner one 2 -> 3 -> 4 -> 2
+        assert_eq!(components(6, 0, &[(0, 1), (1, 2), (2, 3), (3, 4), (4, 2), (4, 1), (3, 5)]), Components{
+            n_weak: 1, n_strong: 3, largest_weak: 6, largest_strong: 4, n_unreachable: 1,
+        });
+    }
+
+    #[test]
+    fn disconnected_pair() {
+        let edges = [(0, 1), (1, 0), (2, 3), (3, 4), (4, 2)];
+        assert_eq!(components(5, 0, &edges), Components{
+            n_weak: 2, n_strong: 2, largest_weak: 3, largest_strong: 3, n_unreachable: 2,
+        });
+    }
+
+    #[test]
+    fn self_loop() {
+        assert_eq!(components(2, 0, &[(0, 0), (1, 0)]), Components{
+            n_weak: 1, n_strong: 2, largest_weak: 2, largest_strong: 1, n_unreachable: 1,
+        });
+        assert_eq!(components(1, 0, &[(0, 0)]), Components{
+            n_weak: 1, n_strong: 1, largest_weak: 1, largest_strong: 1, n_unreachable: 0,
+        });
+    }
 
-        avgdist.iter().fold(0., |x, y| x + y) / avgdist.len() as f64
+    #[test]
+    fn byzantine_nodes_are_removed() {
+        // 1 <-> 0 <-> 2 with 0 Byzantine leaves two isolated correct nodes
+        assert_eq!(components(3, 1, &undirected(&[(0, 1), (0, 2)])), Components{
+            n_weak: 2, n_strong: 2, largest_weak: 1, largest_strong: 1, n_unreachable: 1,
+        });
+        assert_eq!(components(2, 2, &[(0, 1)]), Components::default());
     }
 }
@@ -0,0 +1,194 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::metrics::Metric;
+use super::net::PeerRef;
+
+/// Dependence between the successive samples returned to each node, and
+/// freshness of the samples, over a sliding window of steps
+pub struct Independence {
+    window: usize,
+    step: usize,
+    /// Last sample returned to each node
+    last: Vec<Option<PeerRef>>,
+    /// Step at which each peer was last returned to each node, if it was
+    /// during the window (older entries are pruned every `window` steps)
+    seen: Vec<HashMap<PeerRef, usize>>,
+    /// Samples and counts of each step of the window, the current one last
+    steps: VecDeque<(Vec<PeerRef>, Counts)>,
+    total: Counts,
+    /// Number of times each node was sampled during the window
+    sampled: Vec<usize>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    samples: usize,
+    /// Pairs of consecutive samples of a node, and those of equal samples
+    pairs: usize,
+    equal_pairs: usize,
+    /// Samples already returned to the same node during the window
+    repeats: usize,
+    /// Samples of nodes that were offline
+    stale: usize,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Self) {
+        self.samples += other.samples;
+        self.pairs += other.pairs;
+        self.equal_pairs += other.equal_pairs;
+        self.repeats += other.repeats;
+        self.stale += other.stale;
+    }
+
+    fn sub(&mut self, other: &Self) {
+        self.samples -= other.samples;
+        self.pairs -= other.pairs;
+        self.equal_pairs -= other.equal_pairs;
+        self.repeats -= other.repeats;
+        self.stale -= other.stale;
+    }
+}
+
+impl Independence {
+    pub fn new(nproc: usize, window: usize) -> Self {
+        assert!(window > 0, "independence window must be positive");
+        Self{
+            window,
+            step: 0,
+            last: vec![None; nproc],
+            seen: vec![HashMap::new(); nproc],
+            steps: VecDeque::new(),
+            total: Counts::default(),
+            sampled: vec![0; nproc],
+        }
+    }
+
+    /// Starts a step, forgetting the samples of the step that leaves the window
+    pub fn next_step(&mut self) {
+        self.step += 1;
+        if self.steps.len() == self.window {
+            let (samples, counts) = self.steps.pop_front().unwrap();
+            for peer in samples {
+                self.sampled[peer] -= 1;
+            }
+            self.total.sub(&counts);
+        }
+        if self.step.is_multiple_of(self.window) {
+            let (step, window) = (self.step, self.window);
+            for seen in self.seen.iter_mut() {
+                seen.retain(|_, last| step - *last < window);
+            }
+        }
+        self.steps.push_back((vec![], Counts::default()));
+    }
+
+    /// Sample returned to `node`, which is stale if the sampled node is offline
+    pub fn add(&mut self, node: PeerRef, sample: PeerRef, online: bool) {
+        let mut counts = Counts{ samples: 1, ..Counts::default() };
+        if let Some(last) = self.last[node] {
+            counts.pairs = 1;
+            counts.equal_pairs = (last == sample) as usize;
+        }
+        if let Some(step) = self.seen[node].insert(sample, self.step) {
+            counts.repeats = (self.step - step < self.window) as usize;
+        }
+        counts.stale = !online as usize;
+        self.last[node] = Some(sample);
+        self.sampled[sample] += 1;
+
+        let (samples, step_counts) = self.steps.back_mut().expect("sample added before the first step");
+        samples.push(sample);
+        step_counts.add(&counts);
+        self.total.add(&counts);
+    }
+
+    pub fn headers() -> Vec<&'static str> {
+        vec!["sampAutoCorr", "sampRepeat", "sampStale"]
+    }
+
+    /// Agreement of consecutive samples of the same node corrected for
+    /// chance, given how often each node was sampled (0 for independent
+    /// samples, 1 if they never change); fraction of the samples already
+    /// returned to the same node during the window; fraction of the samples
+    /// of nodes that were offline when they were returned
+    pub fn values(&self) -> Vec<Metric> {
+        let n = self.total.samples as f64;
+        let chance = self.sampled.iter().map(|c| (*c as f64 / n) * (*c as f64 / n)).sum::<f64>();
+        let autocorr = match self.total.pairs {
+            0 => None,
+            _ if chance >= 1. => None,
+            pairs => Some(Metric::Float(
+                (self.total.equal_pairs as f64 / pairs as f64 - chance) / (1. - chance), 4)),
+        };
+        let rate = |x: usize| match self.total.samples {
+            0 => Metric::Optional(None),
+            n => Metric::ratio(x, n, 4),
+        };
+        vec![Metric::optional(autocorr), rate(self.total.repeats), rate(self.total.stale)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(indep: &Independence) -> Vec<Option<f64>> {
+        indep.values().iter().map(|x| x.as_f64()).collect()
+    }
+
+    fn assert_values(indep: &Independence, expected: &[Option<f64>]) {
+        let values = values(indep);
+        let close = |x: &Option<f64>, y: &Option<f64>| match (x, y) {
+            (Some(x), Some(y)) => (x - y).abs() < 1e-12,
+            _ => x == y,
+        };
+        assert!(values.len() == expected.len() && values.iter().zip(expected.iter()).all(|(x, y)| close(x, y)),
+                "{:?} != {:?}", values, expected);
+    }
+
+    #[test]
+    fn hand_fed_samples() {
+        let mut indep = Independence::new(5, 2);
+        assert_values(&indep, &[None, None, None]);
+
+        indep.next_step();
+        indep.add(0, 1, true);
+        indep.add(0, 2, true);
+        indep.add(0, 1, false);
+        indep.add(3, 4, true);
+        // 2 pairs, none equal, with chance (2² + 1² + 1²) / 4² of agreement
+        let chance = 6. / 16.;
+        assert_values(&indep, &[Some(-chance / (1. - chance)), Some(0.25), Some(0.25)]);
+
+        indep.next_step();
+        indep.add(0, 1, true);
+        // 3 pairs, 1 equal; samples 1, 2, 1, 4, 1
+        let chance = 11. / 25.;
+        let autocorr = (1. / 3. - chance) / (1. - chance);
+        assert_values(&indep, &[Some(autocorr), Some(0.4), Some(0.2)]);
+
+        // The first step leaves the window
+        indep.next_step();
+        indep.add(0, 1, true);
+        assert_values(&indep, &[None, Some(1.), Some(0.)]);
+    }
+
+    #[test]
+    fn old_samples_are_forgotten() {
+        let mut indep = Independence::new(3, 2);
+        indep.next_step();
+        indep.add(0, 1, true);
+        indep.add(0, 2, true);
+        indep.next_step();
+        indep.add(0, 1, true);
+        assert_eq!(indep.seen[0].len(), 2);
+        indep.next_step();
+        // Pruned at step 4: 2 was last returned at step 1, 1 at step 2
+        indep.next_step();
+        assert_eq!(indep.seen[0].len(), 0);
+        indep.add(0, 2, true);
+        assert_eq!(indep.seen[0].len(), 1);
+        assert_eq!(values(&indep)[1], Some(0.));
+    }
+}
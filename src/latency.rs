@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use rand::{Rng, RngCore};
+use rand::distributions::{Exp, Pareto};
+
+use super::net::PeerRef;
+use super::util::{hash, seeded_rng};
+
+/// Latencies are expressed in time units; a simulation step lasts one time
+/// unit unless `--step-length` says otherwise. Every latency is at least one
+/// time unit, so a message is never handled at the time at which it was sent.
+pub trait LatencyModel: Send + Sync {
+    fn latency(&self, from: PeerRef, to: PeerRef, rng: &mut dyn RngCore) -> u64;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WhichLatency {
+    /// `constant:<d>`
+    Constant(u64),
+    /// `uniform:<min>:<max>`, bounds included
+    Uniform(u64, u64),
+    /// `exp:<min>:<mean>`, min plus an exponentially distributed delay
+    Exponential(u64, f64),
+    /// `pareto:<min>:<shape>`, heavy-tailed delay with scale min
+    Pareto(u64, f64),
+    /// `coords:<min>:<scale>`, nodes are placed at random in the unit square
+    /// and latency is min plus scale times their euclidean distance
+    Coordinates(u64, f64),
+    /// `trace:<file>`, square matrix of latencies, node i is mapped to row i % size
+    Trace(String),
+}
+
+impl Default for WhichLatency {
+    fn default() -> Self {
+        Self::Constant(1)
+    }
+}
+
+impl std::str::FromStr for WhichLatency {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = s.split(':').collect::<Vec<_>>();
+        let int = |i: usize| args.get(i).and_then(|x| x.parse::<u64>().ok())
+            .ok_or("invalid latency model parameter");
+        let float = |i: usize| args.get(i).and_then(|x| x.parse::<f64>().ok())
+            .ok_or("invalid latency model parameter");
+        match args[0] {
+            "constant" => Ok(Self::Constant(int(1)?)),
+            "uniform" => Ok(Self::Uniform(int(1)?, int(2)?)),
+            "exp" => Ok(Self::Exponential(int(1)?, float(2)?)),
+            "pareto" => Ok(Self::Pareto(int(1)?, float(2)?)),
+            "coords" => Ok(Self::Coordinates(int(1)?, float(2)?)),
+            "trace" if args.len() > 1 => Ok(Self::Trace(args[1..].join(":"))),
+            _ => Err("invalid latency model"),
+        }
+    }
+}
+
+impl WhichLatency {
+    pub fn build(&self, nproc: usize, seed: u64) -> Arc<dyn LatencyModel> {
+        match self {
+            Self::Constant(d) => Arc::new(Constant(*d)),
+            Self::Uniform(min, max) => {
+                assert!(min <= max, "uniform latency: min > max");
+                Arc::new(Uniform(*min, *max))
+            }
+            Self::Exponential(min, mean) => Arc::new(Exponential{
+                min: *min,
+                distr: Exp::new(1. / mean),
+            }),
+            Self::Pareto(min, shape) => Arc::new(HeavyTailed{
+                distr: Pareto::new(std::cmp::max(1, *min) as f64, *shape),
+            }),
+            Self::Coordinates(min, scale) => {
+                let mut rng = seeded_rng(hash(seed, nproc), 0);
+                Arc::new(Coordinates{
+                    min: *min,
+                    scale: *scale,
+                    coords: (0..nproc).map(|_| (rng.gen::<f64>(), rng.gen::<f64>())).collect(),
+                })
+            }
+            Self::Trace(file) => Arc::new(Trace::load(file)),
+        }
+    }
+}
+
+fn at_least_one(d: f64) -> u64 {
+    std::cmp::max(1, d.round() as u64)
+}
+
+struct Constant(u64);
+
+impl LatencyModel for Constant {
+    fn latency(&self, _from: PeerRef, _to: PeerRef, _rng: &mut dyn RngCore) -> u64 {
+        std::cmp::max(1, self.0)
+    }
+}
+
+struct Uniform(u64, u64);
+
+impl LatencyModel for Uniform {
+    fn latency(&self, _from: PeerRef, _to: PeerRef, rng: &mut dyn RngCore) -> u64 {
+        std::cmp::max(1, rng.gen_range(self.0, self.1 + 1))
+    }
+}
+
+struct Exponential {
+    min: u64,
+    distr: Exp,
+}
+
+impl LatencyModel for Exponential {
+    fn latency(&self, _from: PeerRef, _to: PeerRef, rng: &mut dyn RngCore) -> u64 {
+        at_least_one(self.min as f64 + rng.sample(self.distr))
+    }
+}
+
+struct HeavyTailed {
+    distr: Pareto,
+}
+
+impl LatencyModel for HeavyTailed {
+    fn latency(&self, _from: PeerRef, _to: PeerRef, rng: &mut dyn RngCore) -> u64 {
+        at_least_one(rng.sample(self.distr))
+    }
+}
+
+struct Coordinates {
+    min: u64,
+    scale: f64,
+    coords: Vec<(f64, f64)>,
+}
+
+impl LatencyModel for Coordinates {
+    fn latency(&self, from: PeerRef, to: PeerRef, _rng: &mut dyn RngCore) -> u64 {
+        let (x1, y1) = self.coords[from % self.coords.len()];
+        let (x2, y2) = self.coords[to % self.coords.len()];
+        let dist = ((x1 - x2) * (x1 - x2) + (y1 - y2) * (y1 - y2)).sqrt();
+        at_least_one(self.min as f64 + self.scale * dist)
+    }
+}
+
+struct Trace {
+    size: usize,
+    matrix: Vec<u64>,
+}
+
+impl Trace {
+    fn load(file: &str) -> Self {
+        let contents = std::fs::read_to_string(file)
+            .unwrap_or_else(|e| panic!("cannot read latency trace {}: {}", file, e));
+        let rows = contents.lines()
+            .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+            .map(|l| l.split(|c: char| c.is_whitespace() || c == ',')
+                 .filter(|x| !x.is_empty())
+                 .map(|x| at_least_one(x.parse::<f64>().expect("invalid latency in trace")))
+                 .collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let size = rows.len();
+        assert!(size > 0, "empty latency trace");
+        assert!(rows.iter().all(|r| r.len() == size), "latency trace is not a square matrix");
+        Self{
+            size,
+            matrix: rows.into_iter().flatten().collect(),
+        }
+    }
+}
+
+impl LatencyModel for Trace {
+    fn latency(&self, from: PeerRef, to: PeerRef, _rng: &mut dyn RngCore) -> u64 {
+        self.matrix[(from % self.size) * self.size + to % self.size]
+    }
+}
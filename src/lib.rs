@@ -0,0 +1,29 @@
+//! Simulator of random peer sampling protocols under Byzantine attacks.
+//!
+//! A protocol implements `net::App` (with its messages and `net::Metrics`),
+//! and runs on a `net::Simulator`, which delivers messages, fires timers and
+//! applies the network model, churn and adversary of `net::NetParams`.
+//! Protocols that provide samples to others implement `rps::RPS`. The
+//! `basalt-sim` binary is a command line on top of this library.
+
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod net;
+pub mod util;
+pub mod latency;
+pub mod faults;
+pub mod churn;
+pub mod schedule;
+pub mod roles;
+pub mod adversary;
+pub mod victims;
+pub mod engine;
+pub mod bandwidth;
+pub mod metrics;
+pub mod output;
+pub mod graph;
+pub mod rps;
+pub mod uniformity;
+pub mod independence;
+
+pub mod app;
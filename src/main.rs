@@ -1,22 +1,33 @@
-mod net;
-mod util;
-mod graph;
-mod rps;
-
-mod app;
+mod config;
+mod sweep;
 
 use std::sync::{Arc, RwLock};
 
+use rand::{thread_rng, Rng};
+use rayon::prelude::*;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
-use net::{Simulator, App};
+use basalt_sim::{app, metrics, output, rps, util};
+use basalt_sim::adversary::WhichAdversary;
+use basalt_sim::independence::Independence;
+use basalt_sim::uniformity::Uniformity;
+use basalt_sim::net::{Simulator, App, NetParams};
+use metrics::Metric;
+use output::{Format, Output, Records, Sink};
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "bignetrs")]
+// Options given several times take their last value, so that the command line
+// can override a config file
+#[structopt(name = "bignetrs", global_setting = AppSettings::AllArgsOverrideSelf)]
 pub struct Opt {
-    /// Iteration number for a repeated experiment (ignored)
+    /// Iteration number for a repeated experiment (mixed into the random seed)
     #[structopt(short = "i", long = "iteration", default_value = "0")]
     iteration: usize,
 
+    /// Random seed (a random one is drawn if not specified)
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
     /// Number of simulation steps
     #[structopt(short = "T", long = "time", default_value = "100")]
     n_steps: usize,
@@ -24,10 +35,47 @@ pub struct Opt {
     #[structopt(short = "n", long = "nodes", default_value = "1000")]
     nodes: usize,
 
-    /// Show random peer samples instead of metrics after a certain time
+    /// Record random peer samples, as (time, node, sample), instead of
+    /// metrics after a certain time
     #[structopt(short="R", long = "random-samples")]
     random_samples: Option<usize>,
 
+    /// Add to the metrics of RPS protocols the uniformity of the samples of
+    /// the --sample-nodes nodes over sliding windows of this many steps
+    #[structopt(long = "uniformity", conflicts_with = "random-samples")]
+    uniformity: Option<usize>,
+
+    /// Add to the metrics of RPS protocols the autocorrelation, repeat rate
+    /// and stale rate of the samples of the --sample-nodes nodes over
+    /// sliding windows of this many steps
+    #[structopt(long = "independence", conflicts_with = "random-samples")]
+    independence: Option<usize>,
+
+    /// Nodes whose samples are recorded or analyzed: last (correct node), correct,
+    /// random:<k> (correct nodes) or <id>,<id>,...
+    #[structopt(long = "sample-nodes", default_value = "last")]
+    sample_nodes: rps::SampleNodes,
+
+    /// Output format: table, csv or jsonl
+    #[structopt(long = "format", default_value = "table")]
+    format: Format,
+
+    /// Write metrics to this file instead of the standard output
+    #[structopt(long = "output")]
+    output: Option<String>,
+
+    /// Read options from a TOML or JSON experiment file; options given on
+    /// the command line take precedence
+    #[structopt(long = "config")]
+    config: Option<String>,
+
+    /// Print the effective configuration as TOML instead of running
+    #[structopt(long = "dump-config")]
+    dump_config: bool,
+
+    #[structopt(flatten)]
+    net: NetParams,
+
     #[structopt(subcommand)]
     app: WhichApp,
 }
@@ -57,124 +105,316 @@ pub enum WhichApp {
     /// Avalanche consensus algorithm using any RPS
     #[structopt(name = "avalanche")]
     Avalanche(app::avalanche::InitCmd),
+
+    /// Runs every combination of a grid of parameter values, repeatedly
+    #[structopt(name = "sweep")]
+    Sweep(sweep::Sweep),
+}
+
+impl WhichApp {
+    /// Value of the deprecated `-s/--attack-start-time` option of the protocol
+    fn attack_start_time(&self) -> Option<u64> {
+        match self {
+            Self::Brahms(pp) => pp.attack_start_time,
+            Self::SPS(pp) => pp.attack_start_time,
+            Self::BasaltSimple(pp) | Self::Basalt(pp) => pp.attack_start_time,
+            Self::Avalanche(pp) => match &pp.rps {
+                app::avalanche::WhichRPS::Oracle(_) => None,
+                app::avalanche::WhichRPS::Brahms(prps) => prps.attack_start_time,
+                app::avalanche::WhichRPS::SPS(prps) => prps.attack_start_time,
+                app::avalanche::WhichRPS::BasaltSimple(prps) |
+                app::avalanche::WhichRPS::Basalt(prps) => prps.attack_start_time,
+            },
+            Self::RPS(_) | Self::Sweep(_) => None,
+        }
+    }
+}
+
+impl Opt {
+    /// Replaces `-s/--attack-start-time <t>` by `--adversary lurking:<t>`,
+    /// which keeps the behaviour of the option before adversaries existed
+    fn resolve_deprecated(&mut self) {
+        if let Some(start) = self.app.attack_start_time() {
+            assert!(self.net.adversary == WhichAdversary::Flood,
+                    "-s/--attack-start-time cannot be combined with --adversary");
+            self.net.adversary = WhichAdversary::Lurking(start);
+        }
+    }
 }
 
 fn main() {
-    let opt = Opt::from_args();
-    match opt.app {
+    let args = config::args(std::env::args().collect());
+    let matches = Opt::clap().get_matches_from(&args);
+    let mut opt = Opt::from_clap(&matches);
+    opt.resolve_deprecated();
+    // The seed is drawn here so that it is recorded in the run metadata
+    opt.seed = Some(opt.seed.unwrap_or_else(|| thread_rng().gen()));
+    let opt = opt;
+    if opt.dump_config {
+        let mut table = config::dump(&Opt::clap(), &matches);
+        table.remove("config");
+        table.remove("dump-config");
+        // Seeds do not all fit in TOML integers
+        table.insert("seed".to_string(), toml::Value::String(opt.seed.unwrap().to_string()));
+        print!("{}", toml::to_string(&toml::Value::Table(table)).unwrap());
+        return;
+    }
+    if let WhichApp::Sweep(sw) = &opt.app {
+        run_sweep(&opt, sw, &args);
+        return;
+    }
+
+    let mut out = Output::new(opt.format, opt.output.as_deref());
+    out.metadata(&[
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ("seed", opt.seed.unwrap().to_string()),
+        ("iteration", opt.iteration.to_string()),
+        ("command", std::env::args().collect::<Vec<_>>().join(" ")),
+        ("config", opt.config.clone().unwrap_or_default()),
+        ("params", format!("{:?}", opt)),
+    ]);
+    dispatch(&opt, &mut out);
+}
+
+/// Runs the simulation described by `opt`, its metrics going to `out`
+fn dispatch(opt: &Opt, out: &mut dyn Sink) {
+    let seed = util::hash(opt.seed.unwrap(), opt.iteration);
+    match &opt.app {
         WhichApp::RPS(pp) => {
-            if let Some(rs) = opt.random_samples {
-                sim_rps_rng::<app::rps::RPS>(opt.n_steps, opt.nodes, &pp, rs);
-            } else {
-                sim::<app::rps::RPS>(opt.n_steps, opt.nodes, &pp);
-            }
+            run::<app::rps::RPS>(opt, pp.n_byzantine, seed, pp, out);
         }
         WhichApp::Brahms(pp) => {
-            if let Some(rs) = opt.random_samples {
-                sim_rps_rng::<app::brahms::Brahms>(opt.n_steps, opt.nodes, &pp, rs);
-            } else {
-                sim::<app::brahms::Brahms>(opt.n_steps, opt.nodes, &pp);
-            }   
+            run::<app::brahms::Brahms>(opt, pp.n_byzantine, seed, pp, out);
         }
         WhichApp::SPS(pp) => {
-            if let Some(rs) = opt.random_samples {
-                sim_rps_rng::<app::sps::SPS>(opt.n_steps, opt.nodes, &pp, rs);
-            } else {
-                sim::<app::sps::SPS>(opt.n_steps, opt.nodes, &pp);
-            }   
+            run::<app::sps::SPS>(opt, pp.n_byzantine, seed, pp, out);
         }
-        WhichApp::BasaltSimple(mut pp) => {
+        WhichApp::BasaltSimple(pp) => {
+            let mut pp = pp.clone();
             pp.use_hit_counter = false;
-            if let Some(rs) = opt.random_samples {
-                sim_rps_rng::<app::basalt::Basalt>(opt.n_steps, opt.nodes, &pp, rs);
-            } else {
-                sim::<app::basalt::Basalt>(opt.n_steps, opt.nodes, &pp);
-            }
+            run::<app::basalt::Basalt>(opt, pp.n_byzantine, seed, &pp, out);
         }
-        WhichApp::Basalt(mut pp) => {
+        WhichApp::Basalt(pp) => {
+            let mut pp = pp.clone();
             pp.use_hit_counter = true;
-            if let Some(rs) = opt.random_samples {
-                sim_rps_rng::<app::basalt::Basalt>(opt.n_steps, opt.nodes, &pp, rs);
-            } else {
-                sim::<app::basalt::Basalt>(opt.n_steps, opt.nodes, &pp);
-            }
+            run::<app::basalt::Basalt>(opt, pp.n_byzantine, seed, &pp, out);
         }
         WhichApp::Avalanche(pp) => {
             let shared_counter = Arc::new(RwLock::new((0, 0)));
-            match pp.rps {
+            match pp.rps.clone() {
                 app::avalanche::WhichRPS::Oracle(mut prps) => {
                     prps.n_nodes = opt.nodes;
                     let init = app::avalanche::Init::<rps::Oracle>{
-                        args: pp.args,
+                        args: pp.args.clone(),
                         rps_args: prps,
                         shared_counter,
                     };
-                    sim::<app::avalanche::Avalanche<rps::Oracle>>(opt.n_steps, opt.nodes, &init);
+                    sim::<app::avalanche::Avalanche<rps::Oracle>>(opt, init.args.n_byzantine, seed, &init, out);
                 }
                 app::avalanche::WhichRPS::SPS(prps) => {
                     let init = app::avalanche::Init::<app::sps::SPS>{
-                        args: pp.args,
+                        args: pp.args.clone(),
                         rps_args: prps,
                         shared_counter,
                     };
-                    sim::<app::avalanche::Avalanche<app::sps::SPS>>(opt.n_steps, opt.nodes, &init);
+                    sim::<app::avalanche::Avalanche<app::sps::SPS>>(opt, init.args.n_byzantine, seed, &init, out);
                 }
                 app::avalanche::WhichRPS::Brahms(prps) => {
                     let init = app::avalanche::Init::<app::brahms::Brahms>{
-                        args: pp.args,
+                        args: pp.args.clone(),
                         rps_args: prps,
                         shared_counter,
                     };
-                    sim::<app::avalanche::Avalanche<app::brahms::Brahms>>(opt.n_steps, opt.nodes, &init);
+                    sim::<app::avalanche::Avalanche<app::brahms::Brahms>>(opt, init.args.n_byzantine, seed, &init, out);
                 }
                 app::avalanche::WhichRPS::BasaltSimple(mut prps) => {
                     prps.use_hit_counter = false;
                     let init = app::avalanche::Init::<app::basalt::Basalt>{
-                        args: pp.args,
+                        args: pp.args.clone(),
                         rps_args: prps,
                         shared_counter,
                     };
-                    sim::<app::avalanche::Avalanche<app::basalt::Basalt>>(opt.n_steps, opt.nodes, &init);
+                    sim::<app::avalanche::Avalanche<app::basalt::Basalt>>(opt, init.args.n_byzantine, seed, &init, out);
                 }
                 app::avalanche::WhichRPS::Basalt(mut prps) => {
                     prps.use_hit_counter = true;
                     let init = app::avalanche::Init::<app::basalt::Basalt>{
-                        args: pp.args,
+                        args: pp.args.clone(),
                         rps_args: prps,
                         shared_counter,
                     };
-                    sim::<app::avalanche::Avalanche<app::basalt::Basalt>>(opt.n_steps, opt.nodes, &init);
+                    sim::<app::avalanche::Avalanche<app::basalt::Basalt>>(opt, init.args.n_byzantine, seed, &init, out);
                 }
             }
-            
         }
+        WhichApp::Sweep(_) => panic!("sweeps cannot be nested"),
     }
 }
 
-fn sim<A: App + Send>(nsteps: usize, nproc: usize, init: &A::Init) {
-    let mut net = Simulator::<A>::new(nproc, init);
+fn run<A: App + rps::RPS + Send>(opt: &Opt, n_byzantine: usize, seed: u64, init: &A::Init, out: &mut dyn Sink) {
+    if let Some(rs) = opt.random_samples {
+        sim_rps_rng::<A>(opt, n_byzantine, seed, init, rs, out);
+    } else if opt.uniformity.is_some() || opt.independence.is_some() {
+        sim_samples::<A>(opt, n_byzantine, seed, init, out);
+    } else {
+        sim::<A>(opt, n_byzantine, seed, init, out);
+    }
+}
 
-    net.print_header();
-    net.print_metrics();
+fn sim<A: App + Send>(opt: &Opt, n_byzantine: usize, seed: u64, init: &A::Init, out: &mut dyn Sink) {
+    let mut net = Simulator::<A>::new(opt.nodes, n_byzantine, seed, &opt.net, init);
 
-    for _step in 0..nsteps {
+    out.header(&net.headers());
+    out.row(&net.values());
+
+    for _step in 0..opt.n_steps {
+        net.step();
+        out.row(&net.values());
+    }
+}
+
+/// Runs a simulation whose metrics are followed by the analyses of the
+/// samples of the `--sample-nodes` nodes that are enabled
+fn sim_samples<A: App + rps::RPS + Send>(opt: &Opt, n_byzantine: usize, seed: u64, init: &A::Init, out: &mut dyn Sink) {
+    let mut net = Simulator::<A>::new(opt.nodes, n_byzantine, seed, &opt.net, init);
+    let nodes = opt.sample_nodes.select(&net.roles(), opt.nodes, seed);
+    let online = |net: &Simulator<A>| (0..net.nproc()).map(|p| net.is_online(p)).collect::<Vec<_>>();
+    let mut uni = opt.uniformity.map(|window| Uniformity::new(&net.roles(), &online(&net), window));
+    let mut indep = opt.independence.map(|window| Independence::new(net.nproc(), window));
+
+    let values = |net: &Simulator<A>, uni: &Option<Uniformity>, indep: &Option<Independence>| {
+        let mut ret = net.values();
+        ret.extend(uni.iter().flat_map(|x| x.values()));
+        ret.extend(indep.iter().flat_map(|x| x.values()));
+        ret
+    };
+    let mut header = net.headers();
+    if uni.is_some() {
+        header.extend(Uniformity::headers());
+    }
+    if indep.is_some() {
+        header.extend(Independence::headers());
+    }
+    out.header(&header);
+    out.row(&values(&net, &uni, &indep));
+
+    for _step in 0..opt.n_steps {
         net.step();
-        net.print_metrics();
+        uni.iter_mut().for_each(|x| x.next_step(&online(&net)));
+        indep.iter_mut().for_each(|x| x.next_step());
+        for &node in nodes.iter() {
+            let proc = &mut net.processes[node];
+            for r in proc.state.get_samples(&mut proc.rng) {
+                if let Some(uni) = uni.as_mut() {
+                    uni.add(r);
+                }
+                if let Some(indep) = indep.as_mut() {
+                    indep.add(node, r, net.is_online(r));
+                }
+            }
+        }
+        out.row(&values(&net, &uni, &indep));
+    }
+}
+
+/// Runs every point of the grid of `sw` the given number of times, and writes
+/// one row per step of each run, preceded by the swept values and the
+/// iteration, or one row per step of each point with `--aggregate`
+fn run_sweep(opt: &Opt, sw: &sweep::Sweep, args: &[String]) {
+    assert!(sw.iterations > 0 && sw.jobs > 0, "sweep iterations and jobs must be positive");
+    let (names, points) = sweep::grid(&sw.args);
+
+    // Global options given before `sweep`, including those of a config file,
+    // apply to every run; all command lines are parsed before anything runs
+    let prefix = &args[..args.iter().position(|x| x == "sweep").unwrap()];
+    let mut runs = vec![];
+    for point in points.iter() {
+        for i in 0..sw.iterations {
+            let mut run = Opt::from_iter(prefix.iter().chain(point.args.iter()));
+            if let WhichApp::Sweep(_) = run.app {
+                panic!("sweeps cannot be nested");
+            }
+            run.resolve_deprecated();
+            run.seed = run.seed.or(opt.seed);
+            run.iteration += i;
+            runs.push((point, run));
+        }
+    }
+
+    let mut out = Output::new(opt.format, opt.output.as_deref());
+    out.metadata(&[
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ("seed", opt.seed.unwrap().to_string()),
+        ("command", std::env::args().collect::<Vec<_>>().join(" ")),
+        ("config", opt.config.clone().unwrap_or_default()),
+        ("params", format!("{:?}", opt)),
+        ("runs", runs.len().to_string()),
+    ]);
+    let mut columns: Option<Vec<String>> = None;
+    let mut aggregate: Option<sweep::Aggregate> = None;
+    for batch in runs.chunks(sw.jobs) {
+        let records = batch.par_iter()
+            .map(|(_, run)| {
+                let mut records = Records::default();
+                dispatch(run, &mut records);
+                records
+            })
+            .collect::<Vec<_>>();
+        for ((point, run), records) in batch.iter().zip(records) {
+            match &columns {
+                Some(columns) => assert_eq!(columns, &records.columns, "all runs of a sweep must have the same metrics"),
+                None => {
+                    let mut header = names.clone();
+                    if sw.aggregate {
+                        let agg = sweep::Aggregate::new(&records);
+                        header.extend(agg.header(&records.columns));
+                        aggregate = Some(agg);
+                    } else {
+                        header.push("iteration".to_string());
+                        header.extend(records.columns.iter().cloned());
+                    }
+                    out.header(&header.iter().map(|x| x.as_str()).collect::<Vec<_>>());
+                    columns = Some(records.columns.clone());
+                }
+            }
+            let values = point.values.iter().map(|x| Metric::parse(x)).collect::<Vec<_>>();
+            match aggregate.as_mut() {
+                Some(agg) => {
+                    agg.add(&records);
+                    if agg.n_runs() == sw.iterations {
+                        for row in agg.rows() {
+                            out.row(&[values.clone(), row].concat());
+                        }
+                    }
+                }
+                None => {
+                    for row in records.rows {
+                        let mut values = values.clone();
+                        values.push(Metric::int(run.iteration));
+                        values.extend(row);
+                        out.row(&values);
+                    }
+                }
+            }
+        }
     }
 }
 
-fn sim_rps_rng<A: App + rps::RPS + Send>(nsteps: usize, nproc: usize, init: &A::Init, first_output_round: usize) {
-    let mut net = Simulator::<A>::new(nproc, init);
+fn sim_rps_rng<A: App + rps::RPS + Send>(opt: &Opt, n_byzantine: usize, seed: u64, init: &A::Init, first_output_round: usize, out: &mut dyn Sink) {
+    let mut net = Simulator::<A>::new(opt.nodes, n_byzantine, seed, &opt.net, init);
+    let nodes = opt.sample_nodes.select(&net.roles(), opt.nodes, seed);
 
-    for step in 0..nsteps {
+    out.header(&["time", "node", "sample"]);
+    for step in 0..opt.n_steps {
         net.step();
         if step >= first_output_round {
-            let i = nproc - 1;
-            //for i in (nproc/2)..nproc {
-                for r in net.processes[i].state.get_samples() {
-                    println!("{}", r);
+            let time = Metric::Int(net.time() as i64);
+            for &node in nodes.iter() {
+                let proc = &mut net.processes[node];
+                for r in proc.state.get_samples(&mut proc.rng) {
+                    out.row(&[time.clone(), Metric::int(node), Metric::int(r)]);
                 }
-            //}
+            }
         }
     }
 }
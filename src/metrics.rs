@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::output::json_string;
+
+/// Value of a metric at one step; values keep their full precision, and are
+/// only rounded when they are displayed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Metric {
+    Int(i64),
+    /// Real value and the number of decimals with which it is displayed
+    Float(f64, usize),
+    /// Value that may not exist, e.g. a minimum over no nodes
+    Optional(Option<Box<Metric>>),
+    /// Values by key, e.g. by node
+    Keyed(Vec<(u64, Metric)>),
+    /// Number of occurrences of each value
+    Histogram(BTreeMap<u64, u64>),
+    /// Value of a parameter that is not a number, e.g. in a sweep
+    Text(String),
+}
+
+impl Metric {
+    pub fn int(x: usize) -> Self {
+        Metric::Int(x as i64)
+    }
+
+    /// Ratio of two counts, NaN if the denominator is zero
+    pub fn ratio(num: usize, den: usize, decimals: usize) -> Self {
+        Metric::Float(num as f64 / den as f64, decimals)
+    }
+
+    pub fn optional(x: Option<Metric>) -> Self {
+        Metric::Optional(x.map(Box::new))
+    }
+
+    /// Number if `s` is one written in decimal notation, text otherwise
+    pub fn parse(s: &str) -> Self {
+        if let Ok(x) = s.parse::<i64>() {
+            return Metric::Int(x);
+        }
+        match s.parse::<f64>() {
+            Ok(x) if s.chars().all(|c| c.is_ascii_digit() || c == '.' || c == '-') =>
+                Metric::Float(x, s.split('.').nth(1).map_or(0, |d| d.len())),
+            _ => Metric::Text(s.to_string()),
+        }
+    }
+
+    /// Numeric value, if the metric is a single finite number
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Metric::Int(x) => Some(*x as f64),
+            Metric::Float(x, _) if x.is_finite() => Some(*x),
+            Metric::Optional(Some(x)) => x.as_f64(),
+            _ => None,
+        }
+    }
+
+    /// Whether the metric is a number, possibly missing or not finite
+    pub fn is_numeric(&self) -> bool {
+        match self {
+            Metric::Int(_) | Metric::Float(_, _) | Metric::Optional(None) => true,
+            Metric::Optional(Some(x)) => x.is_numeric(),
+            _ => false,
+        }
+    }
+
+    /// Number of decimals with which the metric is displayed
+    pub fn decimals(&self) -> usize {
+        match self {
+            Metric::Float(_, decimals) => *decimals,
+            Metric::Optional(Some(x)) => x.decimals(),
+            _ => 0,
+        }
+    }
+
+    /// JSON representation, with full precision; missing and non-finite
+    /// values are `null`
+    pub fn to_json(&self) -> String {
+        match self {
+            Metric::Int(x) => x.to_string(),
+            Metric::Float(x, _) if x.is_finite() => format!("{:?}", x),
+            Metric::Float(_, _) | Metric::Optional(None) => "null".to_string(),
+            Metric::Optional(Some(x)) => x.to_json(),
+            Metric::Keyed(values) => {
+                let fields = values.iter()
+                    .map(|(k, v)| format!("\"{}\":{}", k, v.to_json()))
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", fields.join(","))
+            }
+            Metric::Histogram(counts) => {
+                let fields = counts.iter()
+                    .map(|(k, n)| format!("\"{}\":{}", k, n))
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", fields.join(","))
+            }
+            Metric::Text(s) => json_string(s),
+        }
+    }
+
+    /// Text representation used in CSV: like in tables, but real values
+    /// keep their full precision
+    pub fn to_csv(&self) -> String {
+        match self {
+            Metric::Float(x, _) => x.to_string(),
+            Metric::Optional(Some(x)) => x.to_csv(),
+            Metric::Keyed(values) if !values.is_empty() => values.iter()
+                .map(|(k, v)| format!("{}={}", k, v.to_csv()))
+                .collect::<Vec<_>>()
+                .join(";"),
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// Text representation used in tables: missing values are `-`, keyed values
+/// and histograms are `<key>=<value>;...`
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Metric::Int(x) => x.to_string(),
+            Metric::Float(x, decimals) => format!("{:.*}", *decimals, x),
+            Metric::Optional(None) => "-".to_string(),
+            Metric::Optional(Some(x)) => x.to_string(),
+            Metric::Keyed(values) if values.is_empty() => "-".to_string(),
+            Metric::Keyed(values) => values.iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(";"),
+            Metric::Histogram(counts) if counts.is_empty() => "-".to_string(),
+            Metric::Histogram(counts) => counts.iter()
+                .map(|(k, n)| format!("{}={}", k, n))
+                .collect::<Vec<_>>()
+                .join(";"),
+            Metric::Text(s) => s.clone(),
+        };
+        // Honour the width of the format string, as for strings
+        f.pad(&text)
+    }
+}
+
+/// Mean and variance of a metric over several runs; summaries of sets of
+/// runs are combined like the metrics of sets of nodes
+#[derive(Clone, Copy, Default)]
+pub struct Summary {
+    n: usize,
+    mean: f64,
+    m2: f64,
+}
+
+impl Summary {
+    pub fn of(x: f64) -> Self {
+        Summary{ n: 1, mean: x, m2: 0. }
+    }
+
+    pub fn combine(&mut self, other: &Self) {
+        if other.n == 0 {
+            return;
+        }
+        let n = self.n + other.n;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.n as f64 / n as f64;
+        self.m2 += other.m2 + delta * delta * (self.n * other.n) as f64 / n as f64;
+        self.n = n;
+    }
+
+    /// Mean, standard deviation and half-width of the 95% confidence interval
+    /// of the mean, the last two missing with fewer than two runs
+    pub fn values(&self, decimals: usize) -> Vec<Metric> {
+        if self.n == 0 {
+            return vec![Metric::Optional(None); 3];
+        }
+        let mean = Metric::Float(self.mean, decimals);
+        if self.n == 1 {
+            return vec![mean, Metric::Optional(None), Metric::Optional(None)];
+        }
+        let sd = (self.m2 / (self.n - 1) as f64).sqrt();
+        let ci = student_t_975(self.n - 1) * sd / (self.n as f64).sqrt();
+        vec![mean, Metric::Float(sd, decimals), Metric::Float(ci, decimals)]
+    }
+}
+
+/// 0.975 quantile of Student's t distribution with `df` degrees of freedom,
+/// that of the closest tabulated lower df above 30
+fn student_t_975(df: usize) -> f64 {
+    const T: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228,
+        2.201, 2.179, 2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086,
+        2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+    ];
+    match df {
+        1..=30 => T[df - 1],
+        31..=39 => 2.042,
+        40..=59 => 2.021,
+        60..=119 => 2.000,
+        120..=999 => 1.980,
+        _ => 1.960,
+    }
+}
+
+/// Histogram with a single occurrence of `x`
+pub fn histogram_of(x: usize) -> BTreeMap<u64, u64> {
+    std::iter::once((x as u64, 1)).collect()
+}
+
+/// Adds the occurrences counted in `other` to `hist`
+pub fn add_histogram(hist: &mut BTreeMap<u64, u64>, other: &BTreeMap<u64, u64>) {
+    for (x, n) in other.iter() {
+        *hist.entry(*x).or_insert(0) += n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(xs: &[f64]) -> Summary {
+        let mut ret = Summary::default();
+        for x in xs {
+            ret.combine(&Summary::of(*x));
+        }
+        ret
+    }
+
+    fn floats(values: &[Metric]) -> Vec<f64> {
+        values.iter().map(|x| x.as_f64().expect("missing value")).collect()
+    }
+
+    #[test]
+    fn csv_keeps_full_precision() {
+        let x = Metric::Float(1. / 3., 2);
+        assert_eq!(x.to_string(), "0.33");
+        assert_eq!(x.to_csv().parse::<f64>().unwrap(), 1. / 3.);
+        let keyed = Metric::Keyed(vec![(4, Metric::Float(0.125, 1)), (7, Metric::Int(3))]);
+        assert_eq!(keyed.to_csv(), "4=0.125;7=3");
+        assert_eq!(Metric::optional(None).to_csv(), "-");
+        assert_eq!(Metric::optional(Some(Metric::Float(2.5, 0))).to_csv(), "2.5");
+    }
+
+    #[test]
+    fn summary_values() {
+        let s = summary(&[2., 4., 4., 4., 5., 5., 7., 9.]);
+        let values = floats(&s.values(4));
+        let sd = (32f64 / 7.).sqrt();
+        assert!((values[0] - 5.).abs() < 1e-12);
+        assert!((values[1] - sd).abs() < 1e-12);
+        assert!((values[2] - 2.365 * sd / 8f64.sqrt()).abs() < 1e-12);
+
+        assert!(Summary::default().values(2).iter().all(|x| x.as_f64().is_none()));
+        let single = Summary::of(3.).values(2);
+        assert_eq!(single[0].as_f64(), Some(3.));
+        assert!(single[1].as_f64().is_none() && single[2].as_f64().is_none());
+    }
+
+    #[test]
+    fn combine_is_concatenation() {
+        let (a, b) = ([2., 4., 4.], [4., 5., 5., 7., 9.]);
+        let mut s = summary(&a);
+        s.combine(&summary(&b));
+        let all = summary(&[&a[..], &b[..]].concat());
+        assert_eq!(s.n, all.n);
+        assert!((s.mean - all.mean).abs() < 1e-12);
+        assert!((s.m2 - all.m2).abs() < 1e-12);
+
+        // The empty summary is neutral on both sides
+        let mut empty = Summary::default();
+        empty.combine(&all);
+        s.combine(&Summary::default());
+        assert_eq!((empty.n, empty.mean, empty.m2), (all.n, all.mean, all.m2));
+        assert_eq!(s.n, all.n);
+    }
+
+    #[test]
+    fn student_t() {
+        assert_eq!(student_t_975(1), 12.706);
+        assert_eq!(student_t_975(10), 2.228);
+        assert_eq!(student_t_975(30), 2.042);
+        assert_eq!(student_t_975(50), 2.021);
+        assert_eq!(student_t_975(100_000), 1.960);
+    }
+}
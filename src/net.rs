@@ -1,30 +1,193 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
 use rayon::prelude::*;
+use structopt::StructOpt;
+
+use rand::{Rng, RngCore};
 
-use rand::{thread_rng, Rng};
+use super::adversary::{Adversary, Behavior, Knowledge, WhichAdversary};
+use super::bandwidth::{Capacity, Link, Overflow};
+use super::churn::{Churn, SybilArrival, WhichChurn};
+use super::engine::{WhichEngine, LOOKAHEAD};
+use super::faults::PerLink;
+use super::latency::{LatencyModel, WhichLatency};
+use super::roles::{NodeRole, RoleTable};
+use super::schedule::{random_partition, Event, Schedule, ScheduledEvent};
+use super::util::{hash, seeded_rng, SimRng};
 
-//use super::metrics::Metric;
+use super::metrics::Metric;
 
 const DEBUG: bool = false;
 const STEP_LENGTH: u64 = 1;
 
 pub type PeerRef = usize;
 
+#[derive(Clone, StructOpt, Debug)]
+pub struct NetParams {
+    /// Latency model: constant:<d>, uniform:<min>:<max>, exp:<min>:<mean>,
+    /// pareto:<min>:<shape>, coords:<min>:<scale> or trace:<file>
+    #[structopt(long = "latency", default_value = "constant:1")]
+    pub latency: WhichLatency,
+
+    /// Message drop probability, either for all links or per link class (e.g. cc=0.1,cb=0.2)
+    #[structopt(long = "drop", default_value = "0")]
+    pub drop: PerLink,
+
+    /// Message duplication probability, either for all links or per link class
+    #[structopt(long = "duplicate", default_value = "0")]
+    pub duplicate: PerLink,
+
+    /// Probability that a message is delayed further, so that it may be overtaken
+    /// by messages sent after it, either for all links or per link class
+    #[structopt(long = "reorder", default_value = "0")]
+    pub reorder: PerLink,
+
+    /// Maximum additional delay of reordered messages
+    #[structopt(long = "reorder-delay", default_value = "4")]
+    pub reorder_delay: u64,
+
+    /// Churn model: none, poisson:<joins>:<leaves> (per step), session:exp:<up>:<down>,
+    /// session:pareto:<shape>:<up>:<down> (mean durations) or trace:<file>
+    #[structopt(long = "churn", default_value = "none")]
+    pub churn: WhichChurn,
+
+    /// Number of correct nodes that are offline at the start (the last ones)
+    #[structopt(long = "initially-offline", default_value = "0")]
+    pub initially_offline: usize,
+
+    /// Arrival of Byzantine nodes: none (all present at the start),
+    /// linear:<start>:<end> or exp:<start>:<end>
+    #[structopt(long = "sybil-arrival", default_value = "none")]
+    pub sybil_arrival: SybilArrival,
+
+    /// Give the Byzantine role to random nodes instead of the first ones
+    #[structopt(long = "random-roles")]
+    pub random_roles: bool,
+
+    /// Scheduled event, <time>:partition:<k> or <time>:heal (can be repeated)
+    #[structopt(long = "event", number_of_values = 1)]
+    pub events: Vec<ScheduledEvent>,
+
+    /// Byzantine strategy: flood, balanced, eclipse:<node>[,<node>...], silent,
+    /// delayed:<time>, honest-then-malicious:<time>, lurking:<time>,
+    /// seed-aware:<p>, sybil:<p>:<budget> or adaptive
+    #[structopt(long = "adversary", default_value = "flood")]
+    pub adversary: WhichAdversary,
+
+    /// Simulation engine: lockstep, event or event:parallel
+    #[structopt(long = "engine", default_value = "lockstep")]
+    pub engine: WhichEngine,
+
+    /// Length of a step in time units; metrics, churn and events are
+    /// handled once per step
+    #[structopt(long = "step-length", default_value = "1")]
+    pub step_length: u64,
+
+    /// Upload capacity of each node per time unit: none, msgs:<n> or bytes:<n>
+    #[structopt(long = "upload", default_value = "none")]
+    pub upload: Capacity,
+
+    /// Download capacity of each node per time unit: none, msgs:<n> or bytes:<n>
+    #[structopt(long = "download", default_value = "none")]
+    pub download: Capacity,
+
+    /// What happens to messages beyond the capacity of a link: queue or drop
+    #[structopt(long = "overflow", default_value = "queue")]
+    pub overflow: Overflow,
+}
+
+/// Default values are those of the command line
+impl Default for NetParams {
+    fn default() -> Self {
+        Self::from_iter(&["net"])
+    }
+}
+
+/// Link behaviour shared by the network handlers of all processes
+#[derive(Clone)]
+struct NetModel {
+    online: Vec<PeerRef>,
+    /// Active partition, empty when the network is not partitioned
+    partition: Vec<usize>,
+    /// Most recent partition, kept after a heal to measure how views re-mix
+    last_partition: Vec<usize>,
+    roles: Arc<RoleTable>,
+    latency: Arc<dyn LatencyModel>,
+    drop: PerLink,
+    duplicate: PerLink,
+    reorder: PerLink,
+    reorder_delay: u64,
+    upload: Capacity,
+    download: Capacity,
+    overflow: Overflow,
+    adversary: Arc<dyn Adversary>,
+    knowledge: Arc<Knowledge>,
+}
+
 pub trait Metrics {
     fn empty() -> Self;
     fn net_combine(&mut self, other: &Self);
     fn headers() -> Vec<&'static str>;
-    fn values(&self) -> Vec<String>;
+    fn values(&self) -> Vec<Metric>;
+}
+
+/// Size of a message on the wire, in bytes
+pub trait MessageSize {
+    fn size(&self) -> usize;
+}
+
+/// Bytes taken by the type of a message
+pub const HEADER_SIZE: usize = 1;
+/// Bytes taken by a peer identifier (an IPv4 address and a port)
+pub const PEER_SIZE: usize = 6;
+
+impl MessageSize for () {
+    fn size(&self) -> usize {
+        HEADER_SIZE
+    }
+}
+
+impl MessageSize for bool {
+    fn size(&self) -> usize {
+        HEADER_SIZE + 1
+    }
 }
 
 pub trait Network<Msg> {
-    fn sample_peers(&self, n: usize) -> Vec<PeerRef>;
+    fn sample_peers(&mut self, n: usize) -> Vec<PeerRef>;
     fn send(&mut self, to: PeerRef, msg: Msg);
+    /// Calls `App::on_timer` with `tag` on this node after `delay` time units
+    /// (at least one); timers are not messages and are not counted as such
+    fn set_timer(&mut self, delay: u64, tag: usize);
     fn time(&self) -> u64;
+    fn rng(&mut self) -> &mut dyn RngCore;
+    /// Partition of a peer in the most recent partition event, even if it
+    /// has been healed since; 0 if the network was never partitioned
+    fn partition(&self, peer: PeerRef) -> usize;
+    fn role(&self, peer: PeerRef) -> NodeRole;
+    fn roles(&self) -> Arc<RoleTable>;
+
+    /// Behavior of Byzantine nodes at the current time
+    fn behavior(&self) -> Behavior;
+    /// Nodes this Byzantine node attacks, at most n of them
+    fn attack_targets(&mut self, n: usize) -> Vec<PeerRef>;
+    /// Byzantine identifiers to advertise to node `to`, at most n of them
+    fn advertised(&mut self, to: PeerRef, n: usize) -> Vec<PeerRef>;
+    /// Whether a peer is specifically targeted by the attack
+    fn is_victim(&self, peer: PeerRef) -> bool;
+    /// Report that this node drew `seed` for slot `slot` of a hash-based
+    /// selection, which the adversary may learn
+    fn observe_seed(&mut self, slot: usize, seed: u64);
+    /// Report the number of Byzantine nodes in the view of this correct node,
+    /// which an omniscient adversary may read at the next step
+    fn report_view(&mut self, n_byzantine: usize, view_size: usize);
 }
 
 pub trait App {
-    type Init: Sync + Send;
-    type Msg: Send;
+    type Init: Sync + Send + Clone;
+    type Msg: Send + Clone + MessageSize;
     type Metrics: Metrics + Send;
 
     fn new() -> Self
@@ -36,157 +199,457 @@ pub trait App {
     fn handle(&mut self, network: &mut dyn Network<Self::Msg>, from: PeerRef, msg: &Self::Msg)
         where Self: Sized;
 
+    fn on_timer(&mut self, network: &mut dyn Network<Self::Msg>, tag: usize)
+        where Self: Sized;
+
     fn metrics(&mut self, network: &mut dyn Network<Self::Msg>) -> Self::Metrics
         where Self: Sized;
 }
 
+/// Seed observations of each node, recorded by `Network::observe_seed`
+type SeedObservations = Vec<(PeerRef, Vec<(usize, Option<u64>)>)>;
+
 struct Message<Msg> {
     from: PeerRef,
     to: PeerRef,
     arrival_time: u64,
-    msg: Msg,
+    payload: Payload<Msg>,
+    size: usize,
+    /// Whether the message went through the download link of the receiver
+    admitted: bool,
+}
+
+enum Payload<Msg> {
+    Msg(Msg),
+    Timer(usize),
+}
+
+impl<Msg> Message<Msg> {
+    fn is_timer(&self) -> bool {
+        matches!(self.payload, Payload::Timer(_))
+    }
+
+    /// Takes the message through the download link of the receiver, once;
+    /// returns the time at which it can be handled, `None` if it is dropped
+    fn download(&mut self, link: &mut Link, model: &NetModel) -> Option<u64> {
+        if self.admitted || self.is_timer() || self.from == self.to {
+            return Some(self.arrival_time);
+        }
+        self.admitted = true;
+        link.admit(model.download, model.overflow, self.arrival_time, self.size)
+    }
+
+    fn deliver<A: App<Msg = Msg>>(&self, state: &mut A, handler: &mut dyn Network<Msg>) {
+        match &self.payload {
+            Payload::Msg(msg) => state.handle(handler, self.from, msg),
+            Payload::Timer(tag) => state.on_timer(handler, *tag),
+        }
+    }
+}
+
+/// Message waiting in the queue of the discrete-event engine, popped by
+/// increasing arrival time then sequence number
+struct Pending<Msg> {
+    seq: u64,
+    message: Box<Message<Msg>>,
+}
+
+impl<Msg> Pending<Msg> {
+    fn key(&self) -> (u64, u64) {
+        (self.message.arrival_time, self.seq)
+    }
+}
+
+impl<Msg> PartialEq for Pending<Msg> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<Msg> Eq for Pending<Msg> {}
+
+impl<Msg> PartialOrd for Pending<Msg> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Msg> Ord for Pending<Msg> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap
+        other.key().cmp(&self.key())
+    }
 }
 
 struct NetHandler<A> where A: App + Send {
     id: PeerRef,
-    nproc: usize,
     time: u64,
+    rng: SimRng,
+    upload: Link,
+    model: Arc<NetModel>,
     outbox: Vec<Box<Message<A::Msg>>>,
     metrics: A::Metrics,
+    n_sent: usize,
+    n_bytes: usize,
     n_recv: usize,
+    n_dropped: usize,
+    n_duplicated: usize,
+    observed: Vec<(usize, Option<u64>)>,
+    reported: Option<(usize, usize)>,
+}
+
+impl<A> NetHandler<A> where A: App + Send {
+    fn new(id: PeerRef, rng: SimRng, model: Arc<NetModel>, n_recv: usize) -> Self {
+        Self{
+            id,
+            time: 0,
+            rng,
+            upload: Link::default(),
+            model,
+            outbox: Vec::new(),
+            metrics: A::Metrics::empty(),
+            n_sent: 0,
+            n_bytes: 0,
+            n_recv,
+            n_dropped: 0,
+            n_duplicated: 0,
+            observed: Vec::new(),
+            reported: None,
+        }
+    }
+
+    fn happens(&mut self, p: f64) -> bool {
+        p > 0. && self.rng.gen_bool(p)
+    }
 }
 
 impl<A> Network<A::Msg> for NetHandler<A> where A: App + Send {
-    fn sample_peers(&self, n: usize) -> Vec<PeerRef> {
-        let mut rng = thread_rng();
-        if n <= self.nproc / 10 {
+    fn sample_peers(&mut self, n: usize) -> Vec<PeerRef> {
+        // Peers are sampled among the nodes currently online, which makes
+        // this the bootstrap service of joining nodes
+        let online = &self.model.online;
+        if n <= online.len() / 10 {
             let mut res = Vec::new();
             while res.len() < n {
-                let i = rng.gen_range(0, self.nproc);
+                let i = online[self.rng.gen_range(0, online.len())];
                 if i != self.id && !res.contains(&i) {
                     res.push(i);
                 }
             }
             res
         } else {
-            let mut vec = (0..self.nproc).collect::<Vec<_>>();
-            rng.shuffle(&mut vec[..]);
+            let mut vec = online.clone();
+            self.rng.shuffle(&mut vec[..]);
             vec.iter().cloned().take(n).collect::<Vec<_>>()
         }
     }
 
     fn send(&mut self, to: PeerRef, msg: A::Msg) {
-        //let latency = (100 + (self.id + to) % 100) as u64;
-        let latency = STEP_LENGTH;
+        let size = msg.size();
+        self.n_sent += 1;
+        if to == self.id {
+            // Messages to self do not go through any link
+            self.outbox.push(Box::new(Message{
+                from: self.id,
+                to,
+                arrival_time: self.time + STEP_LENGTH,
+                payload: Payload::Msg(msg),
+                size,
+                admitted: false,
+            }));
+            return;
+        }
+
+        // Messages leave once the upload link of the sender has capacity for them
+        let departure = match self.upload.admit(self.model.upload, self.model.overflow, self.time, size) {
+            Some(t) => t,
+            None => {
+                self.n_dropped += 1;
+                return;
+            }
+        };
+        // Only messages that go through the upload link use bandwidth
+        self.n_bytes += size;
+
+        let link = (self.model.roles.is_byzantine(self.id), self.model.roles.is_byzantine(to));
+        if self.happens(self.model.drop.get(link.0, link.1)) {
+            self.n_dropped += 1;
+            return;
+        }
+        let copies = if self.happens(self.model.duplicate.get(link.0, link.1)) {
+            self.n_duplicated += 1;
+            vec![msg.clone(), msg]
+        } else {
+            vec![msg]
+        };
+        for msg in copies {
+            let mut latency = self.model.latency.latency(self.id, to, &mut self.rng);
+            if self.happens(self.model.reorder.get(link.0, link.1)) {
+                latency += self.rng.gen_range(1, self.model.reorder_delay + 1);
+            }
+            self.outbox.push(Box::new(Message{
+                from: self.id,
+                to,
+                arrival_time: departure + latency,
+                payload: Payload::Msg(msg),
+                size,
+                admitted: false,
+            }));
+        }
+    }
+
+    fn set_timer(&mut self, delay: u64, tag: usize) {
+        assert!(delay > 0, "timer delay must be positive");
         self.outbox.push(Box::new(Message{
             from: self.id,
-            to: to,
-            arrival_time: self.time + latency,
-            msg: msg
+            to: self.id,
+            arrival_time: self.time + delay,
+            payload: Payload::Timer(tag),
+            size: 0,
+            admitted: false,
         }));
     }
 
     fn time(&self) -> u64 {
         self.time
     }
+
+    fn rng(&mut self) -> &mut dyn RngCore {
+        &mut self.rng
+    }
+
+    fn partition(&self, peer: PeerRef) -> usize {
+        self.model.last_partition.get(peer).cloned().unwrap_or(0)
+    }
+
+    fn role(&self, peer: PeerRef) -> NodeRole {
+        self.model.roles.role(peer)
+    }
+
+    fn roles(&self) -> Arc<RoleTable> {
+        self.model.roles.clone()
+    }
+
+    fn behavior(&self) -> Behavior {
+        self.model.adversary.behavior(self.id, self.time, &self.model.knowledge)
+    }
+
+    fn attack_targets(&mut self, n: usize) -> Vec<PeerRef> {
+        self.model.adversary.targets(self.id, self.time, n, &self.model.online, &self.model.knowledge, &mut self.rng)
+    }
+
+    fn advertised(&mut self, to: PeerRef, n: usize) -> Vec<PeerRef> {
+        self.model.adversary.advertised(to, n, &self.model.knowledge, &mut self.rng)
+    }
+
+    fn is_victim(&self, peer: PeerRef) -> bool {
+        self.model.adversary.victims().binary_search(&peer).is_ok()
+    }
+
+    fn observe_seed(&mut self, slot: usize, seed: u64) {
+        let leak = self.model.adversary.seed_leak();
+        if leak > 0. && !self.model.roles.is_byzantine(self.id) {
+            let learnt = self.happens(leak);
+            self.observed.push((slot, if learnt { Some(seed) } else { None }));
+        }
+    }
+
+    fn report_view(&mut self, n_byzantine: usize, view_size: usize) {
+        if self.model.adversary.reads_metrics() {
+            self.reported = Some((n_byzantine, view_size));
+        }
+    }
 }
 
 pub struct Proc<A> where A: App + Send {
     id: PeerRef,
     inbox: Vec<Box<Message<A::Msg>>>,
+    online: bool,
+    restart: bool,
+    upload: Link,
+    download: Link,
+    pub rng: SimRng,
     pub state: A,
 }
 
 pub struct Simulator<A> where A: App + Send {
     nproc: usize,
 
+    engine: WhichEngine,
     step_length: u64,
     time: u64,
+    model: Arc<NetModel>,
+    churn: Churn,
+    schedule: Schedule,
+    rng: SimRng,
+    init: A::Init,
     pub processes: Vec<Proc<A>>,
 
     metrics: A::Metrics,
     n_sent: usize,
     n_recv: usize,
+    n_dropped: usize,
+    n_duplicated: usize,
+    n_online: usize,
+    /// Bytes sent by each node during the current step
+    upload: Vec<usize>,
 }
 
 impl<A: App + Send> Simulator<A> {
-    pub fn new(nproc: usize, init: &A::Init) -> Self {
+    pub fn new(nproc: usize, n_byzantine: usize, seed: u64, params: &NetParams, init: &A::Init) -> Self {
+        assert!(params.step_length > 0, "step length must be positive");
+        let mut roles = RoleTable::new(nproc, n_byzantine, params.random_roles, seed);
+        let churn = Churn::new(&params.churn, &params.sybil_arrival, nproc, &roles, seed);
+        // Sybil identities minted by the adversary come after the other
+        // nodes, and are offline until they win a known seed
+        let pool = roles.mint(params.adversary.sybil_budget());
+        let n_ids = nproc + pool.len();
+
+        let correct = roles.correct();
+        assert!(params.initially_offline <= correct.len());
+        let mut offline = vec![false; n_ids];
+        for i in correct[correct.len() - params.initially_offline..].iter()
+            .chain(churn.late_arrivals().iter())
+            .chain(pool.iter()) {
+            offline[*i] = true;
+        }
+        let online = (0..n_ids).filter(|i| !offline[*i]).collect::<Vec<_>>();
+        let n_online = online.len();
+
         let mut net = Self {
-            nproc,
-            step_length: STEP_LENGTH,
+            nproc: n_ids,
+            engine: params.engine,
+            step_length: params.step_length,
             time: 0,
+            model: Arc::new(NetModel{
+                knowledge: Arc::new(Knowledge::new(&roles, &online, pool)),
+                adversary: params.adversary.build(&roles),
+                online,
+                partition: vec![],
+                last_partition: vec![],
+                roles: Arc::new(roles),
+                latency: params.latency.build(nproc, seed),
+                drop: params.drop,
+                duplicate: params.duplicate,
+                reorder: params.reorder,
+                reorder_delay: std::cmp::max(1, params.reorder_delay),
+                upload: params.upload,
+                download: params.download,
+                overflow: params.overflow,
+            }),
+            churn,
+            schedule: Schedule::new(&params.events),
+            rng: seeded_rng(hash(seed, nproc + 2), 0),
+            init: init.clone(),
             processes: Vec::new(),
             metrics: A::Metrics::empty(),
             n_sent: 0,
             n_recv: 0,
+            n_dropped: 0,
+            n_duplicated: 0,
+            n_online,
+            upload: vec![0; n_ids],
         };
-        for i in 0..nproc {
+        for (i, is_offline) in offline.iter().enumerate() {
             net.processes.push(Proc{
-                id: i, 
+                id: i,
                 inbox: Vec::new(),
+                online: !is_offline,
+                restart: false,
+                upload: Link::default(),
+                download: Link::default(),
+                rng: seeded_rng(seed, i),
                 state: A::new()
             });
         }
+        let model = &net.model;
         let out = net.processes.par_iter_mut()
             .map(|proc| {
-                let mut handler = NetHandler{
-                    id: proc.id,
-                    nproc,
-                    time: 0,
-                    outbox: Vec::new(),
-                    metrics: A::Metrics::empty(),
-                    n_recv: 0,
-                };
-                proc.state.init(proc.id, &mut handler, init);
-                handler.metrics = proc.state.metrics(&mut handler);
+                let mut handler = NetHandler::new(proc.id, proc.rng.clone(), model.clone(), 0);
+                handler.upload = proc.upload;
+                if proc.online {
+                    proc.state.init(proc.id, &mut handler, init);
+                    handler.metrics = proc.state.metrics(&mut handler);
+                }
+                proc.rng = handler.rng.clone();
+                proc.upload = handler.upload;
                 handler
             })
             .collect::<Vec<_>>();
-        net.incorporate(out);
+        net.incorporate(out, true);
         net
     }
 
-    fn incorporate(&mut self, mut out: Vec<NetHandler<A>>) {
-        if DEBUG {
-            eprintln!("Begin metric collection...");
-        }
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Number of node identifiers, including the Sybil identities minted by the adversary
+    pub fn nproc(&self) -> usize {
+        self.nproc
+    }
+
+    pub fn roles(&self) -> Arc<RoleTable> {
+        self.model.roles.clone()
+    }
+
+    pub fn is_online(&self, peer: PeerRef) -> bool {
+        self.processes[peer].online
+    }
 
-        self.metrics = out.par_iter_mut()
-            .map(|x| std::mem::replace(&mut x.metrics, A::Metrics::empty()))
-            .reduce(|| A::Metrics::empty(),
-                    |mut a, b| { a.net_combine(&b); a });
+    fn incorporate(&mut self, mut out: Vec<NetHandler<A>>, with_metrics: bool) {
+        if with_metrics {
+            if DEBUG {
+                eprintln!("Begin metric collection...");
+            }
+
+            self.metrics = out.par_iter_mut()
+                .map(|x| std::mem::replace(&mut x.metrics, A::Metrics::empty()))
+                .reduce(A::Metrics::empty,
+                        |mut a, b| { a.net_combine(&b); a });
+        }
 
-        self.n_recv = out.par_iter_mut()
-            .map(|x| x.n_recv)
-            .reduce(|| 0, |a, b| a + b);
+        self.n_sent += out.iter().map(|x| x.n_sent).sum::<usize>();
+        self.n_recv += out.iter().map(|x| x.n_recv).sum::<usize>();
+        self.n_dropped += out.iter().map(|x| x.n_dropped).sum::<usize>();
+        self.n_duplicated += out.iter().map(|x| x.n_duplicated).sum::<usize>();
+        for handler in out.iter() {
+            self.upload[handler.id] += handler.n_bytes;
+        }
 
         if DEBUG {
             eprintln!("Begin message exchange (1)...");
         }
         const N_CHUNKS: usize = 128;
         let chunk_size: usize = ((self.nproc - 1) / N_CHUNKS) + 1;
-        let mut msgs = out.par_chunks_mut(chunk_size*4)
+        let partition = &self.model.partition;
+        let (mut msgs, n_cut): (Vec<_>, Vec<_>) = out.par_chunks_mut(chunk_size*4)
             .map(|chunk| {
                 let mut msgs_by_dest_chunk = (0..N_CHUNKS).map(|_| Vec::new())
                     .collect::<Vec<_>>();
+                let mut n_cut = 0;
                 for proc in chunk.iter_mut() {
                     for msg in proc.outbox.drain(..) {
+                        // Traffic between different partitions is lost
+                        if !partition.is_empty() && partition[msg.from] != partition[msg.to] {
+                            n_cut += 1;
+                            continue;
+                        }
                         let dest_chunk = msg.to / chunk_size;
                         msgs_by_dest_chunk[dest_chunk].push(msg);
                     }
                 }
-                msgs_by_dest_chunk
-            }).collect::<Vec<_>>();
+                (msgs_by_dest_chunk, n_cut)
+            }).unzip();
+        self.n_dropped += n_cut.iter().sum::<usize>();
 
         if DEBUG {
             eprintln!("Begin message exchange (2)...");
         }
-        self.n_sent = 0;
         let mut msgs_by_dest_chunk = (0..N_CHUNKS).map(|_| Vec::new())
             .collect::<Vec<_>>();
         for mut bit in msgs.drain(..) {
             for (chunk_messages, chunk) in bit.drain(..).zip(0..N_CHUNKS) {
-                self.n_sent += chunk_messages.len();
                 msgs_by_dest_chunk[chunk].push(chunk_messages);
             }
         }
@@ -209,29 +672,66 @@ impl<A: App + Send> Simulator<A> {
 
         // Slow (non-parallel) version of message exchange
         if false {
-            for i in 0..self.nproc {
-                for msg_box in out[i].outbox.drain(..) {
+            for (i, handler) in out.iter_mut().enumerate() {
+                for msg_box in handler.outbox.drain(..) {
                     assert!(msg_box.from == i);
                     self.processes[msg_box.to].inbox.push(msg_box);
                 }
             }
         }
+
+        let observed = out.iter_mut()
+            .filter(|h| !h.observed.is_empty())
+            .map(|h| (h.id, std::mem::take(&mut h.observed)))
+            .collect::<Vec<_>>();
+        let reported = out.iter()
+            .filter_map(|h| h.reported.map(|(n_byzantine, view_size)| (h.id, n_byzantine, view_size)))
+            .collect::<Vec<_>>();
+        drop(out);
+        self.inform_adversary(observed, reported);
     }
 
-    pub fn print_header(&self) {
-        print!("{:10} {:10} {:10}", "time", "n_sent", "n_recv");
-        for v in A::Metrics::headers() {
-            print!(" {:10}", v);
+    /// Seeds learnt by the adversary and views reported to it,
+    /// made available at the next step
+    fn inform_adversary(&mut self, observed: SeedObservations, reported: Vec<(PeerRef, usize, usize)>) {
+        if !observed.is_empty() || !reported.is_empty() {
+            let knowledge = Arc::make_mut(&mut Arc::make_mut(&mut self.model).knowledge);
+            for (node, seeds) in observed {
+                for (slot, seed) in seeds {
+                    knowledge.learn(node, slot, seed);
+                }
+            }
+            knowledge.update_kept();
+            if !reported.is_empty() {
+                knowledge.rank(reported);
+            }
         }
-        println!("");
     }
 
-    pub fn print_metrics(&self) {
-        print!("{:<10} {:<10} {:<10}", self.time, self.n_sent, self.n_recv);
-        for v in self.metrics.values() {
-            print!(" {:10}", v);
-        }
-        println!("");
+    pub fn headers(&self) -> Vec<&'static str> {
+        let mut ret = vec!["time", "n_online", "n_sent", "n_recv", "n_dropped", "n_duplicated",
+                           "n_bytes", "bytesNode", "maxUpload"];
+        ret.extend(A::Metrics::headers());
+        ret
+    }
+
+    pub fn values(&self) -> Vec<Metric> {
+        // Upload of nodes per step, averaged over the nodes online
+        let n_bytes = self.upload.iter().sum::<usize>();
+        let max_upload = self.upload.iter().cloned().max().unwrap_or(0);
+        let mut ret = vec![
+            Metric::Int(self.time as i64),
+            Metric::int(self.n_online),
+            Metric::int(self.n_sent),
+            Metric::int(self.n_recv),
+            Metric::int(self.n_dropped),
+            Metric::int(self.n_duplicated),
+            Metric::int(n_bytes),
+            Metric::ratio(n_bytes, std::cmp::max(1, self.n_online), 1),
+            Metric::int(max_upload),
+        ];
+        ret.extend(self.metrics.values());
+        ret
     }
 
     pub fn step(&mut self) {
@@ -239,32 +739,220 @@ impl<A: App + Send> Simulator<A> {
             eprintln!("Begin step...");
         }
 
-        let nproc = self.nproc;
+        self.apply_churn();
+        self.apply_events();
+
+        self.n_sent = 0;
+        self.n_recv = 0;
+        self.n_dropped = 0;
+        self.n_duplicated = 0;
+        self.upload.iter_mut().for_each(|x| *x = 0);
+
         let until_time = self.time + self.step_length;
+        match self.engine {
+            WhichEngine::Lockstep => self.run_window(self.time, until_time, true),
+            WhichEngine::Event => {
+                self.run_events(until_time);
+                // Nothing is left to handle, only metrics are collected
+                self.run_window(until_time, until_time, true);
+            }
+            WhichEngine::ParallelEvent => {
+                let mut time = self.time;
+                while time < until_time {
+                    let end = std::cmp::min(time + LOOKAHEAD, until_time);
+                    self.run_window(time, end, end == until_time);
+                    time = end;
+                }
+            }
+        }
+        self.time = until_time;
+    }
+
+    /// Each node handles the messages that arrive until `until_time`
+    /// in parallel with the other nodes
+    fn run_window(&mut self, time: u64, until_time: u64, with_metrics: bool) {
+        let model = &self.model;
+        let init = &self.init;
         let out = self.processes.par_iter_mut()
             .map(|proc| {
                 let (mut to_handle, remaining): (Vec<_>, Vec<_>) = proc.inbox.drain(..).partition(|msg| msg.arrival_time <= until_time);
                 proc.inbox = remaining;
 
-                let mut handler = NetHandler{
-                    id: proc.id,
-                    nproc,
-                    time: 0,
-                    outbox: Vec::new(),
-                    metrics: A::Metrics::empty(),
-                    n_recv: to_handle.len(),
-                };
-                to_handle.sort_by(|a, b| a.arrival_time.cmp(&b.arrival_time));
-                for message in to_handle {
+                let mut handler = NetHandler::new(proc.id, proc.rng.clone(), model.clone(), 0);
+                handler.upload = proc.upload;
+                handler.time = time;
+                if !proc.online {
+                    // Messages to nodes that are offline are lost
+                    handler.n_dropped = to_handle.iter().filter(|msg| !msg.is_timer()).count();
+                    return handler;
+                }
+                if proc.restart {
+                    proc.restart = false;
+                    proc.state = A::new();
+                    proc.state.init(proc.id, &mut handler, init);
+                }
+
+                to_handle.sort_by_key(|msg| msg.arrival_time);
+                for mut message in to_handle {
+                    match message.download(&mut proc.download, model) {
+                        None => {
+                            handler.n_dropped += 1;
+                            continue;
+                        }
+                        Some(t) if t > until_time => {
+                            message.arrival_time = t;
+                            proc.inbox.push(message);
+                            continue;
+                        }
+                        Some(t) => message.arrival_time = t,
+                    }
+                    if !message.is_timer() {
+                        handler.n_recv += 1;
+                    }
                     handler.time = message.arrival_time;
-                    proc.state.handle(&mut handler, message.from, &message.msg);
+                    message.deliver(&mut proc.state, &mut handler);
+                }
+                if with_metrics {
+                    handler.metrics = proc.state.metrics(&mut handler);
                 }
-                handler.metrics = proc.state.metrics(&mut handler);
+                proc.rng = handler.rng.clone();
+                proc.upload = handler.upload;
                 handler
             })
             .collect::<Vec<_>>();
-        self.incorporate(out);
-        self.time = until_time;
+        self.incorporate(out, with_metrics);
+    }
+
+    /// Handles the messages that arrive until `until_time` one at a time,
+    /// in order of arrival, so that messages sent during the step are
+    /// handled during the step if they arrive early enough
+    fn run_events(&mut self, until_time: u64) {
+        let mut queue = BinaryHeap::new();
+        let mut seq = 0;
+        for proc in self.processes.iter_mut() {
+            for message in proc.inbox.drain(..) {
+                queue.push(Pending{ seq, message });
+                seq += 1;
+            }
+        }
+
+        let mut observed = vec![];
+        for i in 0..self.nproc {
+            let proc = &mut self.processes[i];
+            if !proc.online || !proc.restart {
+                continue;
+            }
+            proc.restart = false;
+            proc.state = A::new();
+            let mut handler = NetHandler::<A>::new(proc.id, proc.rng.clone(), self.model.clone(), 0);
+            handler.upload = proc.upload;
+            handler.time = self.time;
+            proc.state.init(proc.id, &mut handler, &self.init);
+            proc.rng = handler.rng.clone();
+            proc.upload = handler.upload;
+            self.enqueue(handler, &mut queue, &mut seq, &mut observed);
+        }
+
+        while queue.peek().is_some_and(|p| p.message.arrival_time <= until_time) {
+            let mut message = queue.pop().unwrap().message;
+            let proc = &mut self.processes[message.to];
+            let n_msgs = if message.is_timer() { 0 } else { 1 };
+            if !proc.online {
+                // Messages to nodes that are offline are lost
+                self.n_dropped += n_msgs;
+                continue;
+            }
+            match message.download(&mut proc.download, &self.model) {
+                None => {
+                    self.n_dropped += 1;
+                    continue;
+                }
+                Some(t) if t > message.arrival_time => {
+                    message.arrival_time = t;
+                    queue.push(Pending{ seq, message });
+                    seq += 1;
+                    continue;
+                }
+                Some(_) => (),
+            }
+            let mut handler = NetHandler::<A>::new(proc.id, proc.rng.clone(), self.model.clone(), n_msgs);
+            handler.upload = proc.upload;
+            handler.time = message.arrival_time;
+            message.deliver(&mut proc.state, &mut handler);
+            proc.rng = handler.rng.clone();
+            proc.upload = handler.upload;
+            self.enqueue(handler, &mut queue, &mut seq, &mut observed);
+        }
+
+        for pending in queue.into_sorted_vec().into_iter().rev() {
+            let message = pending.message;
+            self.processes[message.to].inbox.push(message);
+        }
+        self.inform_adversary(observed, vec![]);
+    }
+
+    fn enqueue(&mut self, mut handler: NetHandler<A>, queue: &mut BinaryHeap<Pending<A::Msg>>, seq: &mut u64, observed: &mut SeedObservations) {
+        self.n_sent += handler.n_sent;
+        self.n_recv += handler.n_recv;
+        self.n_dropped += handler.n_dropped;
+        self.n_duplicated += handler.n_duplicated;
+        self.upload[handler.id] += handler.n_bytes;
+        let partition = &self.model.partition;
+        for message in handler.outbox.drain(..) {
+            // Traffic between different partitions is lost
+            if !partition.is_empty() && partition[message.from] != partition[message.to] {
+                self.n_dropped += 1;
+                continue;
+            }
+            queue.push(Pending{ seq: *seq, message });
+            *seq += 1;
+        }
+        if !handler.observed.is_empty() {
+            observed.push((handler.id, handler.observed));
+        }
+    }
+
+    fn apply_churn(&mut self) {
+        let online = self.processes.iter().map(|p| p.online).collect::<Vec<_>>();
+        let mut transitions = self.churn.transitions(self.time, &online);
+        transitions.extend(self.model.knowledge.to_deploy(&online).into_iter().map(|b| (b, true)));
+        if transitions.is_empty() {
+            return;
+        }
+
+        for (i, join) in transitions {
+            let proc = &mut self.processes[i];
+            proc.online = join;
+            proc.restart = join;
+            if !join {
+                // Timers die with the state of the node
+                proc.inbox.retain(|msg| !msg.is_timer());
+            }
+        }
+        let mut model = (*self.model).clone();
+        model.online = self.processes.iter()
+            .filter(|p| p.online)
+            .map(|p| p.id)
+            .collect();
+        self.n_online = model.online.len();
+        Arc::make_mut(&mut model.knowledge).set_online(&model.roles, &model.online);
+        self.model = Arc::new(model);
+    }
+
+    fn apply_events(&mut self) {
+        for event in self.schedule.due(self.time) {
+            let mut model = (*self.model).clone();
+            match event {
+                Event::Partition(k) => {
+                    model.partition = random_partition(self.nproc, k, &mut self.rng);
+                    model.last_partition = model.partition.clone();
+                }
+                Event::Heal => {
+                    model.partition = vec![];
+                }
+            }
+            self.model = Arc::new(model);
+        }
     }
 }
 
@@ -0,0 +1,154 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use super::metrics::Metric;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// `table`, space-padded columns
+    #[default]
+    Table,
+    /// `csv`, with run metadata as leading `# key: value` comment lines
+    Csv,
+    /// `jsonl`, one JSON object per line, the first one holding run metadata
+    Jsonl,
+}
+
+impl std::str::FromStr for Format {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Self::Table),
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::Jsonl),
+            _ => Err("invalid output format (expected table, csv or jsonl)"),
+        }
+    }
+}
+
+/// Receives the metrics of a run: the columns, then one row per step
+pub trait Sink {
+    fn header(&mut self, columns: &[&str]);
+    fn row(&mut self, values: &[Metric]);
+}
+
+/// Keeps the metrics of a run in memory
+#[derive(Default)]
+pub struct Records {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Metric>>,
+}
+
+impl Sink for Records {
+    fn header(&mut self, columns: &[&str]) {
+        self.columns = columns.iter().map(|c| c.to_string()).collect();
+    }
+
+    fn row(&mut self, values: &[Metric]) {
+        self.rows.push(values.to_vec());
+    }
+}
+
+/// Writes the metrics of each step as records of the chosen format
+pub struct Output {
+    format: Format,
+    out: Box<dyn Write>,
+    columns: Vec<String>,
+}
+
+impl Output {
+    /// Writes to the file at `path`, or to the standard output
+    pub fn new(format: Format, path: Option<&str>) -> Self {
+        let out: Box<dyn Write> = match path {
+            Some(path) => Box::new(BufWriter::new(File::create(path).expect("cannot create output file"))),
+            None => Box::new(io::stdout()),
+        };
+        Self{ format, out, columns: vec![] }
+    }
+
+    /// Run metadata, written as a header record; the table format has none,
+    /// so that its output stays the same as before, but the seed is printed
+    /// on the standard error so that the run can be reproduced
+    pub fn metadata(&mut self, fields: &[(&str, String)]) {
+        match self.format {
+            Format::Table => {
+                if let Some((_, seed)) = fields.iter().find(|(key, _)| *key == "seed") {
+                    eprintln!("# seed: {}", seed);
+                }
+            }
+            Format::Csv => {
+                for (key, value) in fields {
+                    writeln!(self.out, "# {}: {}", key, value.replace('\n', " ")).expect("write error");
+                }
+            }
+            Format::Jsonl => {
+                let fields = fields.iter()
+                    .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+                    .collect::<Vec<_>>();
+                writeln!(self.out, "{{\"type\":\"metadata\",{}}}", fields.join(",")).expect("write error");
+            }
+        }
+    }
+}
+
+impl Sink for Output {
+    fn header(&mut self, columns: &[&str]) {
+        self.columns = columns.iter().map(|c| c.to_string()).collect();
+        match self.format {
+            Format::Table => {
+                let line = columns.iter().map(|c| format!("{:10}", c)).collect::<Vec<_>>();
+                writeln!(self.out, "{}", line.join(" ")).expect("write error");
+            }
+            Format::Csv => {
+                let line = columns.iter().map(|c| csv_field(c)).collect::<Vec<_>>();
+                writeln!(self.out, "{}", line.join(",")).expect("write error");
+            }
+            Format::Jsonl => (),
+        }
+    }
+
+    fn row(&mut self, values: &[Metric]) {
+        assert_eq!(values.len(), self.columns.len(), "row does not match the header");
+        match self.format {
+            Format::Table => {
+                let line = values.iter().map(|v| format!("{:10}", v)).collect::<Vec<_>>();
+                writeln!(self.out, "{}", line.join(" ")).expect("write error");
+            }
+            Format::Csv => {
+                let line = values.iter().map(|v| csv_field(&v.to_csv())).collect::<Vec<_>>();
+                writeln!(self.out, "{}", line.join(",")).expect("write error");
+            }
+            Format::Jsonl => {
+                let fields = self.columns.iter().zip(values.iter())
+                    .map(|(c, v)| format!("{}:{}", json_string(c), v.to_json()))
+                    .collect::<Vec<_>>();
+                writeln!(self.out, "{{{}}}", fields.join(",")).expect("write error");
+            }
+        }
+        self.out.flush().expect("write error");
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
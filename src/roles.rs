@@ -0,0 +1,64 @@
+use rand::Rng;
+
+use super::net::PeerRef;
+use super::util::{hash, seeded_rng};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NodeRole {
+    Correct,
+    Byzantine,
+}
+
+impl NodeRole {
+    pub fn is_byzantine(self) -> bool {
+        self == NodeRole::Byzantine
+    }
+}
+
+/// Role of every node, indexed by node identifier
+#[derive(Clone, Debug)]
+pub struct RoleTable {
+    roles: Vec<NodeRole>,
+}
+
+impl RoleTable {
+    /// The Byzantine role is given to the first `n_byzantine` nodes, or to
+    /// `n_byzantine` nodes chosen at random if `random` is set
+    pub fn new(nproc: usize, n_byzantine: usize, random: bool, seed: u64) -> Self {
+        assert!(n_byzantine <= nproc, "more Byzantine nodes than nodes");
+        let mut ids = (0..nproc).collect::<Vec<PeerRef>>();
+        if random {
+            seeded_rng(hash(seed, nproc + 3), 0).shuffle(&mut ids[..]);
+        }
+        let mut roles = vec![NodeRole::Correct; nproc];
+        for i in ids.iter().take(n_byzantine) {
+            roles[*i] = NodeRole::Byzantine;
+        }
+        Self{ roles }
+    }
+
+    /// Adds `n` Byzantine identities after the existing nodes and returns them
+    pub fn mint(&mut self, n: usize) -> Vec<PeerRef> {
+        let first = self.roles.len();
+        self.roles.resize(first + n, NodeRole::Byzantine);
+        (first..first + n).collect()
+    }
+
+    pub fn role(&self, peer: PeerRef) -> NodeRole {
+        self.roles[peer]
+    }
+
+    pub fn is_byzantine(&self, peer: PeerRef) -> bool {
+        self.roles[peer].is_byzantine()
+    }
+
+    /// Byzantine nodes, by increasing identifier
+    pub fn byzantine(&self) -> Vec<PeerRef> {
+        (0..self.roles.len()).filter(|i| self.is_byzantine(*i)).collect()
+    }
+
+    /// Correct nodes, by increasing identifier
+    pub fn correct(&self) -> Vec<PeerRef> {
+        (0..self.roles.len()).filter(|i| !self.is_byzantine(*i)).collect()
+    }
+}
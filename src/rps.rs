@@ -1,9 +1,13 @@
+use rand::RngCore;
+
 use super::net::{App, PeerRef, Network};
 use super::net::Metrics as NetMetrics;
+use super::metrics::Metric;
+use super::roles::RoleTable;
 
 use structopt::StructOpt;
 
-use super::util::sample_nocopy;
+use super::util::{hash, sample, sample_nocopy, seeded_rng};
 
 pub struct EmptyMetrics;
 impl NetMetrics for EmptyMetrics {
@@ -15,16 +19,70 @@ impl NetMetrics for EmptyMetrics {
     fn headers() -> Vec<&'static str> {
         vec![]
     }
-    fn values(&self) -> Vec<String> {
+    fn values(&self) -> Vec<Metric> {
         vec![]
     }
 }
 
 pub trait RPS {
-    fn get_samples(&mut self) -> Vec<PeerRef>;
+    fn get_samples(&mut self, rng: &mut dyn RngCore) -> Vec<PeerRef>;
     fn clear_samples(&mut self);
 }
 
+/// Nodes whose random samples are recorded
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum SampleNodes {
+    /// `last`, the last correct node
+    #[default]
+    Last,
+    /// `correct`, all correct nodes
+    Correct,
+    /// `random:<k>`, k correct nodes drawn at random
+    Random(usize),
+    /// `<id>,<id>,...`, the given nodes
+    Nodes(Vec<PeerRef>),
+}
+
+impl std::str::FromStr for SampleNodes {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = s.split(':').collect::<Vec<_>>();
+        match args[0] {
+            "last" => Ok(Self::Last),
+            "correct" => Ok(Self::Correct),
+            "random" => match args.get(1).and_then(|x| x.parse::<usize>().ok()) {
+                Some(k) if k > 0 => Ok(Self::Random(k)),
+                _ => Err("invalid number of random nodes"),
+            },
+            _ => s.split(',')
+                .map(|x| x.trim().parse::<PeerRef>())
+                .collect::<Result<Vec<_>, _>>()
+                .map(Self::Nodes)
+                .map_err(|_| "invalid sample nodes (expected last, correct, random:<k> or <id>,<id>,...)"),
+        }
+    }
+}
+
+impl SampleNodes {
+    /// Selected nodes in increasing order, random ones being drawn from `seed`
+    pub fn select(&self, roles: &RoleTable, nproc: usize, seed: u64) -> Vec<PeerRef> {
+        let correct = roles.correct();
+        let mut ret = match self {
+            Self::Last => vec![*correct.last().expect("no correct node")],
+            Self::Correct => correct,
+            Self::Random(k) => sample(&correct, *k, &mut seeded_rng(hash(seed, nproc + 4), 0)),
+            Self::Nodes(nodes) => {
+                assert!(nodes.iter().all(|x| *x < nproc), "sample node out of range");
+                nodes.clone()
+            }
+        };
+        ret.sort_unstable();
+        ret.dedup();
+        ret
+    }
+}
+
 #[derive(Clone, Default, StructOpt, Debug)]
 pub struct OracleInit {
     #[structopt(skip)]
@@ -70,16 +128,19 @@ impl App for Oracle {
     fn handle(&mut self, _network: &mut dyn Network<Self::Msg>, _from: PeerRef, _msg: &Self::Msg) {
     }
 
+    fn on_timer(&mut self, _network: &mut dyn Network<Self::Msg>, _tag: usize) {
+    }
+
     fn metrics(&mut self, _network: &mut dyn Network<Self::Msg>) -> Self::Metrics {
         Self::Metrics::empty()
     }
 }
 
 impl RPS for Oracle {
-    fn get_samples(&mut self) -> Vec<PeerRef> {
-        self.counter = self.counter + 1;
-        if (self.counter + self.my_id) % self.params.period == 0 {
-            sample_nocopy(&mut self.nodes[..], self.params.count)
+    fn get_samples(&mut self, rng: &mut dyn RngCore) -> Vec<PeerRef> {
+        self.counter += 1;
+        if (self.counter + self.my_id).is_multiple_of(self.params.period) {
+            sample_nocopy(&mut self.nodes[..], self.params.count, rng)
         } else {
             vec![]
         }
@@ -0,0 +1,75 @@
+use rand::{Rng, RngCore};
+
+use super::net::PeerRef;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// `partition:<k>`, split all nodes uniformly at random into k partitions;
+    /// messages between different partitions are dropped
+    Partition(usize),
+    /// `heal`, remove all partitions
+    Heal,
+}
+
+/// An event happening at the beginning of the step starting at `time`,
+/// parsed from `<time>:<event>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledEvent {
+    pub time: u64,
+    pub event: Event,
+}
+
+impl std::str::FromStr for ScheduledEvent {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = s.split(':').collect::<Vec<_>>();
+        let time = args[0].parse::<u64>().map_err(|_| "invalid event time")?;
+        let event = match args.get(1) {
+            Some(&"partition") => {
+                match args.get(2).and_then(|x| x.parse::<usize>().ok()) {
+                    Some(k) if k > 0 => Event::Partition(k),
+                    _ => return Err("invalid number of partitions"),
+                }
+            }
+            Some(&"heal") => Event::Heal,
+            _ => return Err("invalid event (expected partition:<k> or heal)"),
+        };
+        Ok(Self{ time, event })
+    }
+}
+
+pub struct Schedule {
+    events: Vec<ScheduledEvent>,
+    pos: usize,
+}
+
+impl Schedule {
+    pub fn new(events: &[ScheduledEvent]) -> Self {
+        let mut events = events.to_vec();
+        events.sort_by_key(|e| e.time);
+        Self{ events, pos: 0 }
+    }
+
+    /// Events that are due at time `time`
+    pub fn due(&mut self, time: u64) -> Vec<Event> {
+        let mut ret = vec![];
+        while self.pos < self.events.len() && self.events[self.pos].time <= time {
+            ret.push(self.events[self.pos].event.clone());
+            self.pos += 1;
+        }
+        ret
+    }
+}
+
+/// Partition of each node, nodes being shuffled before being assigned
+/// to partitions in a round-robin fashion so that sizes are balanced
+pub fn random_partition(nproc: usize, k: usize, rng: &mut dyn RngCore) -> Vec<usize> {
+    let mut nodes = (0..nproc).collect::<Vec<PeerRef>>();
+    rng.shuffle(&mut nodes[..]);
+    let mut partition = vec![0; nproc];
+    for (i, node) in nodes.iter().enumerate() {
+        partition[*node] = i % k;
+    }
+    partition
+}
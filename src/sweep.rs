@@ -0,0 +1,283 @@
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use super::metrics::{Metric, Summary};
+use super::output::Records;
+
+#[derive(StructOpt, Debug)]
+#[structopt(setting = AppSettings::TrailingVarArg)]
+pub struct Sweep {
+    /// Number of iterations of each combination of values, with distinct seeds
+    #[structopt(short = "N", long = "iterations", default_value = "1")]
+    pub iterations: usize,
+
+    /// Write, for each combination of values and step, the mean, standard
+    /// deviation and 95% confidence interval of every numeric metric over
+    /// the iterations instead of the metrics of each run
+    #[structopt(short = "A", long = "aggregate")]
+    pub aggregate: bool,
+
+    /// Number of runs executed at the same time
+    #[structopt(short = "j", long = "jobs", default_value = "1")]
+    pub jobs: usize,
+
+    /// Arguments of the runs, after `--` if they start with global options.
+    /// Values can be lists a,b,c, ranges a..b or a..b:s (a..b step s) with b
+    /// included and a step required for non-integers, or {x,y} for values
+    /// that are not numbers
+    pub args: Vec<String>,
+}
+
+/// One combination of values of the swept arguments
+pub struct Point {
+    /// Value of each swept argument
+    pub values: Vec<String>,
+    /// Arguments of the run
+    pub args: Vec<String>,
+}
+
+/// Names of the swept arguments (the options they are given to), and the
+/// combinations of their values, the last argument varying fastest
+pub fn grid(args: &[String]) -> (Vec<String>, Vec<Point>) {
+    let mut names: Vec<String> = vec![];
+    let mut points = vec![Point{ values: vec![], args: vec![] }];
+    let mut i = 0;
+    while i < args.len() {
+        let (prefix, value) = match args[i].find('=') {
+            Some(pos) if args[i].starts_with("--") => args[i].split_at(pos + 1),
+            _ => ("", args[i].as_str()),
+        };
+        let option = match prefix {
+            "" if i > 0 => args[i - 1].as_str(),
+            _ => prefix.trim_end_matches('='),
+        };
+        let step = match args.get(i + 1).map(|x| x.as_str()) {
+            Some("step") => Some(args.get(i + 2).expect("missing sweep step").as_str()),
+            _ => None,
+        };
+        i += if step.is_some() { 3 } else { 1 };
+
+        let values = match values(value, step) {
+            Some(values) => values,
+            None => {
+                for p in points.iter_mut() {
+                    p.args.push(format!("{}{}", prefix, value));
+                }
+                continue;
+            }
+        };
+        let mut name = match option.strip_prefix('-') {
+            Some(option) => option.trim_start_matches('-').to_string(),
+            None => format!("arg{}", names.len()),
+        };
+        if names.contains(&name) {
+            name = format!("{}_{}", name, names.len());
+        }
+        names.push(name);
+
+        points = points.into_iter()
+            .flat_map(|p| values.iter().map(move |v| {
+                let mut values = p.values.clone();
+                values.push(v.clone());
+                let mut args = p.args.clone();
+                args.push(format!("{}{}", prefix, v));
+                Point{ values, args }
+            }).collect::<Vec<_>>())
+            .collect();
+    }
+    (names, points)
+}
+
+/// Values of a swept argument, `None` if it has a single value
+fn values(s: &str, step: Option<&str>) -> Option<Vec<String>> {
+    if let Some(list) = s.strip_prefix('{').and_then(|x| x.strip_suffix('}')) {
+        return Some(list.split(',').map(|x| x.to_string()).collect());
+    }
+    if let Some((a, b)) = s.split_once("..") {
+        let (b, colon_step) = match b.split_once(':') {
+            Some((b, s)) => (b, Some(s)),
+            None => (b, None),
+        };
+        let int = |x: &str| x.parse::<i64>().ok();
+        let int_step = match step.or(colon_step) {
+            Some(x) => int(x),
+            None => Some(1),
+        };
+        if let (Some(a), Some(b), Some(step)) = (int(a), int(b), int_step) {
+            assert!(step > 0, "invalid sweep step");
+            assert!(a <= b, "empty sweep range");
+            return Some((a..=b).step_by(step as usize).map(|x| x.to_string()).collect());
+        }
+        let float = |x: &str| x.parse::<f64>().ok();
+        if let (Some(fa), Some(fb)) = (float(a), float(b)) {
+            let step_str = step.or(colon_step).expect("missing step of floating-point sweep range");
+            let fstep = float(step_str).filter(|x| *x > 0.).expect("invalid sweep step");
+            assert!(fa <= fb, "empty sweep range");
+            // Values are written with as many decimals as the bounds and step,
+            // and b is included despite rounding errors
+            let decimals = [a, b, step_str].iter()
+                .map(|x| x.split_once('.').map(|(_, d)| d.len()).unwrap_or(0))
+                .max().unwrap();
+            let n = ((fb - fa) / fstep + 1e-9).floor() as usize;
+            return Some((0..=n).map(|k| format!("{:.*}", decimals, fa + k as f64 * fstep)).collect());
+        }
+    }
+    assert!(step.is_none(), "sweep step given after a value that is not a range");
+    let items = s.split(',').collect::<Vec<_>>();
+    if items.len() > 1 && items.iter().all(|x| x.parse::<f64>().is_ok()) {
+        return Some(items.iter().map(|x| x.to_string()).collect());
+    }
+    None
+}
+
+/// Statistics of the numeric metrics at each step over the runs of a point
+pub struct Aggregate {
+    /// Index of the time column
+    time: usize,
+    /// Index of the numeric columns, and their number of decimals
+    numeric: Vec<(usize, usize)>,
+    /// Time at each step
+    times: Vec<Metric>,
+    stats: Vec<Vec<Summary>>,
+    n_runs: usize,
+}
+
+impl Aggregate {
+    /// Numeric columns are those of the first row of the first run
+    pub fn new(first: &Records) -> Self {
+        let time = first.columns.iter().position(|c| c == "time").expect("no time column");
+        let numeric = first.rows[0].iter().enumerate()
+            .filter(|(i, x)| *i != time && x.is_numeric())
+            .map(|(i, x)| (i, x.decimals() + 2))
+            .collect();
+        Self{ time, numeric, times: vec![], stats: vec![], n_runs: 0 }
+    }
+
+    pub fn header(&self, columns: &[String]) -> Vec<String> {
+        let mut ret = vec!["time".to_string(), "runs".to_string()];
+        for (i, _) in self.numeric.iter() {
+            for stat in ["mean", "sd", "ci95"].iter() {
+                ret.push(format!("{}_{}", columns[*i], stat));
+            }
+        }
+        ret
+    }
+
+    pub fn n_runs(&self) -> usize {
+        self.n_runs
+    }
+
+    pub fn add(&mut self, records: &Records) {
+        if self.n_runs == 0 {
+            self.times = records.rows.iter().map(|row| row[self.time].clone()).collect();
+            self.stats = vec![vec![Summary::default(); self.numeric.len()]; records.rows.len()];
+        }
+        assert_eq!(self.stats.len(), records.rows.len(), "aggregated runs must have the same number of steps");
+        for (stats, row) in self.stats.iter_mut().zip(records.rows.iter()) {
+            for (stat, (i, _)) in stats.iter_mut().zip(self.numeric.iter()) {
+                if let Some(x) = row[*i].as_f64() {
+                    stat.combine(&Summary::of(x));
+                }
+            }
+        }
+        self.n_runs += 1;
+    }
+
+    /// Rows of statistics of the runs added since the last call
+    pub fn rows(&mut self) -> Vec<Vec<Metric>> {
+        let ret = self.times.iter().zip(self.stats.iter())
+            .map(|(time, stats)| {
+                let mut row = vec![time.clone(), Metric::int(self.n_runs)];
+                for (stat, (_, decimals)) in stats.iter().zip(self.numeric.iter()) {
+                    row.extend(stat.values(*decimals));
+                }
+                row
+            })
+            .collect();
+        self.n_runs = 0;
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(values("1..4", None), Some(strings(&["1", "2", "3", "4"])));
+        assert_eq!(values("100..500:100", None), Some(strings(&["100", "200", "300", "400", "500"])));
+        assert_eq!(values("100..450", Some("100")), Some(strings(&["100", "200", "300", "400"])));
+        assert_eq!(values("3..3", None), Some(strings(&["3"])));
+    }
+
+    #[test]
+    fn float_ranges() {
+        assert_eq!(values("0.1..0.5:0.1", None), Some(strings(&["0.1", "0.2", "0.3", "0.4", "0.5"])));
+        assert_eq!(values("0..1", Some("0.25")), Some(strings(&["0.00", "0.25", "0.50", "0.75", "1.00"])));
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(values("20,50,100", None), Some(strings(&["20", "50", "100"])));
+        assert_eq!(values("0.1,0.2", None), Some(strings(&["0.1", "0.2"])));
+        assert_eq!(values("{flood,seed-aware:0.5}", None), Some(strings(&["flood", "seed-aware:0.5"])));
+        assert_eq!(values("{a}", None), Some(strings(&["a"])));
+    }
+
+    #[test]
+    fn single_values() {
+        assert_eq!(values("10", None), None);
+        assert_eq!(values("flood", None), None);
+        assert_eq!(values("cc=0.1,cb=0.2", None), None);
+        assert_eq!(values("1..x", None), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "empty sweep range")]
+    fn reversed_range() {
+        values("5..1", None);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid sweep step")]
+    fn zero_step() {
+        values("1..5:0", None);
+    }
+
+    #[test]
+    #[should_panic(expected = "missing step")]
+    fn float_range_without_step() {
+        values("0.1..0.5", None);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a range")]
+    fn step_after_single_value() {
+        values("5", Some("2"));
+    }
+
+    #[test]
+    fn cartesian_product() {
+        let (names, points) = grid(&strings(&["basalt", "-t", "1..2", "--view-size=5,6", "-f", "10"]));
+        assert_eq!(names, strings(&["t", "view-size"]));
+        let values = points.iter().map(|p| p.values.clone()).collect::<Vec<_>>();
+        assert_eq!(values, vec![
+            strings(&["1", "5"]), strings(&["1", "6"]),
+            strings(&["2", "5"]), strings(&["2", "6"]),
+        ]);
+        assert_eq!(points[1].args, strings(&["basalt", "-t", "1", "--view-size=6", "-f", "10"]));
+    }
+
+    #[test]
+    fn repeated_option_names() {
+        let (names, points) = grid(&strings(&["--event", "{1:heal,2:heal}", "--event", "3..4", "x", "1,2"]));
+        assert_eq!(names, strings(&["event", "event_1", "arg2"]));
+        assert_eq!(points.len(), 8);
+        assert_eq!(points[0].args, strings(&["--event", "1:heal", "--event", "3", "x", "1"]));
+    }
+}
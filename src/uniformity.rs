@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+
+use super::metrics::Metric;
+use super::net::PeerRef;
+use super::roles::RoleTable;
+
+/// Distribution of the samples returned to some nodes over a sliding window
+/// of steps, compared with the ideal one: correct nodes online sampled
+/// uniformly, and Byzantine nodes in the same proportion as among the nodes
+/// online. Identities that are offline, such as Sybil identities that the
+/// adversary has not deployed, are not part of the ideal distribution.
+pub struct Uniformity {
+    is_byzantine: Vec<bool>,
+    /// Nodes online at the current step
+    online: Vec<bool>,
+    window: usize,
+    /// Samples of each step of the window, the current one last
+    steps: VecDeque<Vec<PeerRef>>,
+    /// Number of times each node was sampled during the window
+    counts: Vec<usize>,
+    n_samples: usize,
+    n_byzantine_samples: usize,
+}
+
+impl Uniformity {
+    /// `online` tells for each node identifier whether it is online
+    pub fn new(roles: &RoleTable, online: &[bool], window: usize) -> Self {
+        assert!(window > 0, "uniformity window must be positive");
+        Self{
+            is_byzantine: (0..online.len()).map(|i| roles.is_byzantine(i)).collect(),
+            online: online.to_vec(),
+            window,
+            steps: VecDeque::new(),
+            counts: vec![0; online.len()],
+            n_samples: 0,
+            n_byzantine_samples: 0,
+        }
+    }
+
+    /// Starts a step with the nodes that are now online, forgetting the
+    /// samples of the step that leaves the window
+    pub fn next_step(&mut self, online: &[bool]) {
+        self.online.copy_from_slice(online);
+        if self.steps.len() == self.window {
+            for peer in self.steps.pop_front().unwrap() {
+                self.counts[peer] -= 1;
+                self.n_samples -= 1;
+                self.n_byzantine_samples -= self.is_byzantine[peer] as usize;
+            }
+        }
+        self.steps.push_back(vec![]);
+    }
+
+    pub fn add(&mut self, sample: PeerRef) {
+        self.counts[sample] += 1;
+        self.n_samples += 1;
+        self.n_byzantine_samples += self.is_byzantine[sample] as usize;
+        self.steps.back_mut().expect("sample added before the first step").push(sample);
+    }
+
+    pub fn headers() -> Vec<&'static str> {
+        vec!["uniN", "uniChi2", "uniChi2P", "uniKL", "uniTVD", "uniByzRatio"]
+    }
+
+    /// Number of samples in the window; chi-squared statistic of the samples
+    /// of correct nodes online against the uniform distribution over them,
+    /// and its p-value; Kullback-Leibler divergence from, and total variation
+    /// distance to, that uniform distribution; fraction of Byzantine samples
+    /// over the fraction of Byzantine nodes among the nodes online. The
+    /// chi-squared test is only meaningful with about 5 samples or more per
+    /// correct node.
+    pub fn values(&self) -> Vec<Metric> {
+        let n_online = self.online.iter().filter(|x| **x).count();
+        let n_byzantine = self.online.iter().zip(self.is_byzantine.iter())
+            .filter(|(online, byz)| **online && **byz)
+            .count();
+        let n_correct = n_online - n_byzantine;
+        let counts = self.counts.iter()
+            .zip(self.is_byzantine.iter().zip(self.online.iter()))
+            .filter(|(_, (byz, online))| !**byz && **online)
+            .map(|(c, _)| *c as f64)
+            .collect::<Vec<_>>();
+        let n = counts.iter().sum::<f64>() as usize;
+        let mut ret = vec![Metric::int(self.n_samples)];
+        if n == 0 || n_correct < 2 {
+            ret.extend(vec![Metric::Optional(None); 4]);
+        } else {
+            let expected = n as f64 / n_correct as f64;
+            let chi2 = counts.iter().map(|c| (c - expected) * (c - expected) / expected).sum::<f64>();
+            let observed = counts.iter().map(|c| c / n as f64).collect::<Vec<_>>();
+            let uniform = vec![1. / n_correct as f64; n_correct];
+            ret.push(Metric::Float(chi2, 1));
+            ret.push(Metric::Float(chi2_pvalue(chi2, n_correct - 1), 4));
+            ret.push(Metric::Float(kl_divergence(&observed, &uniform), 4));
+            ret.push(Metric::Float(total_variation(&observed, &uniform), 4));
+        }
+        ret.push(match (self.n_samples, n_byzantine) {
+            (0, _) | (_, 0) => Metric::Optional(None),
+            _ => Metric::Float(
+                (self.n_byzantine_samples as f64 / self.n_samples as f64)
+                    / (n_byzantine as f64 / n_online as f64),
+                3),
+        });
+        ret
+    }
+}
+
+/// Kullback-Leibler divergence of the distribution `p` from `q`, which must
+/// be positive wherever `p` is
+fn kl_divergence(p: &[f64], q: &[f64]) -> f64 {
+    p.iter().zip(q.iter())
+        .filter(|(p, _)| **p > 0.)
+        .map(|(p, q)| p * (p / q).ln())
+        .sum()
+}
+
+/// Total variation distance between the distributions `p` and `q`
+fn total_variation(p: &[f64], q: &[f64]) -> f64 {
+    p.iter().zip(q.iter()).map(|(p, q)| (p - q).abs()).sum::<f64>() / 2.
+}
+
+/// Probability that a chi-squared variable with `df` degrees of freedom
+/// exceeds `x`, by the Wilson-Hilferty approximation
+fn chi2_pvalue(x: f64, df: usize) -> f64 {
+    let k = df as f64;
+    let s = (2. / (9. * k)).sqrt();
+    let z = ((x / k).cbrt() - (1. - s * s)) / s;
+    0.5 * erfc(z / std::f64::consts::SQRT_2)
+}
+
+/// Complementary error function, with an absolute error below 1.5e-7
+/// (Abramowitz and Stegun, 7.1.26)
+fn erfc(x: f64) -> f64 {
+    if x < 0. {
+        return 2. - erfc(-x);
+    }
+    let t = 1. / (1. + 0.3275911 * x);
+    let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    poly * (-x * x).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(x: f64, y: f64, eps: f64) -> bool {
+        (x - y).abs() < eps
+    }
+
+    #[test]
+    fn chi2_pvalues() {
+        // 0.95 and 0.01 quantiles of chi-squared distributions
+        assert!(close(chi2_pvalue(11.070, 5), 0.05, 1e-3));
+        assert!(close(chi2_pvalue(18.307, 10), 0.05, 1e-3));
+        assert!(close(chi2_pvalue(124.342, 100), 0.05, 1e-3));
+        assert!(close(chi2_pvalue(2.558, 10), 0.99, 2e-3));
+        assert!(close(chi2_pvalue(30., 30), 0.4657, 1e-3));
+        assert!(chi2_pvalue(1000., 10) < 1e-6);
+    }
+
+    #[test]
+    fn erfc_values() {
+        assert!(close(erfc(0.), 1., 1e-7));
+        assert!(close(erfc(1.), 0.157299207, 1e-6));
+        assert!(close(erfc(-1.), 1.842700793, 1e-6));
+        assert!(close(erfc(3.), 2.20904970e-5, 1e-6));
+    }
+
+    #[test]
+    fn divergences() {
+        let uniform = vec![0.25; 4];
+        assert_eq!(kl_divergence(&uniform, &uniform), 0.);
+        assert_eq!(total_variation(&uniform, &uniform), 0.);
+        assert_eq!(total_variation(&[0.5, 0.5, 0., 0.], &[0., 0., 0.5, 0.5]), 1.);
+        assert!(close(kl_divergence(&[0.5, 0.5, 0., 0.], &uniform), 2f64.ln(), 1e-12));
+        assert!(close(total_variation(&[1., 0., 0., 0.], &uniform), 0.75, 1e-12));
+    }
+
+    #[test]
+    fn uniform_samples() {
+        let roles = RoleTable::new(10, 2, false, 0);
+        let online = [true; 10];
+        let mut uni = Uniformity::new(&roles, &online, 2);
+        uni.next_step(&online);
+        for _ in 0..3 {
+            for i in 0..10 {
+                uni.add(i);
+            }
+        }
+        let values = uni.values().iter().map(|x| x.as_f64().unwrap()).collect::<Vec<_>>();
+        let expected = [30., 0., 1., 0., 0., 1.];
+        assert!(values.iter().zip(expected.iter()).all(|(x, y)| close(*x, *y, 1e-6)), "{:?}", values);
+
+        // Samples leave the window after two steps
+        uni.next_step(&online);
+        uni.next_step(&online);
+        assert_eq!(uni.values()[0].as_f64(), Some(0.));
+        assert_eq!(uni.values()[1].as_f64(), None);
+    }
+
+    #[test]
+    fn offline_identities_are_not_expected() {
+        // 8 correct and 2 Byzantine nodes, 40 Sybil identities of which 2
+        // are deployed, and the last correct node is offline
+        let mut roles = RoleTable::new(10, 2, false, 0);
+        let pool = roles.mint(40);
+        let mut online = [true; 50];
+        online[9] = false;
+        for p in pool[2..].iter() {
+            online[*p] = false;
+        }
+        let mut uni = Uniformity::new(&roles, &online, 2);
+        uni.next_step(&online);
+        for _ in 0..3 {
+            for i in (0..9).chain(pool[..2].iter().cloned()) {
+                uni.add(i);
+            }
+        }
+        let values = uni.values().iter().map(|x| x.as_f64().unwrap()).collect::<Vec<_>>();
+        let expected = [33., 0., 1., 0., 0., 1.];
+        assert!(values.iter().zip(expected.iter()).all(|(x, y)| close(*x, *y, 1e-6)), "{:?}", values);
+    }
+}
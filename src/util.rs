@@ -1,9 +1,11 @@
 use std::hash::{Hash, Hasher};
 use fasthash::*;
-use rand::{thread_rng, Rng};
+use rand::{Rng, RngCore, SeedableRng};
+use rand::prng::XorShiftRng;
 
 use super::net::PeerRef;
 
+pub type SimRng = XorShiftRng;
 
 pub fn either_or_if_both<T: Clone>(a: &Option<T>, b: &Option<T>, f: fn(&T, &T) -> T) -> Option<T> {
     match (a, b) {
@@ -21,12 +23,19 @@ pub fn hash(seed: u64, peer: PeerRef) -> u64 {
     s.finish()
 }
 
-pub fn sample<T: PartialEq + Clone>(from: &[T], n: usize) -> Vec<T> {
+/// Deterministic RNG for stream number `stream` of a run seeded with `seed`
+pub fn seeded_rng(seed: u64, stream: usize) -> SimRng {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&hash(seed, 2 * stream).to_le_bytes());
+    bytes[8..].copy_from_slice(&hash(seed, 2 * stream + 1).to_le_bytes());
+    SimRng::from_seed(bytes)
+}
+
+pub fn sample<T: PartialEq + Clone>(from: &[T], n: usize, rng: &mut dyn RngCore) -> Vec<T> {
     if n >= from.len() {
         return from.to_vec();
     }
 
-    let mut rng = thread_rng();
     if n >= from.len() / 4 {
         let mut ret = from.to_vec();
         rng.shuffle(&mut ret[..]);
@@ -43,15 +52,14 @@ pub fn sample<T: PartialEq + Clone>(from: &[T], n: usize) -> Vec<T> {
     }
 }
 
-pub fn sample_nocopy<T: PartialEq + Clone>(from: &mut [T], n: usize) -> Vec<T> {
+pub fn sample_nocopy<T: PartialEq + Clone>(from: &mut [T], n: usize, rng: &mut dyn RngCore) -> Vec<T> {
     if n >= from.len() {
         return from.to_vec();
     }
 
-    let mut rng = thread_rng();
     if n >= from.len() / 4 {
         rng.shuffle(from);
-        from[..n].iter().cloned().collect::<Vec<T>>()
+        from[..n].to_vec()
     } else {
         let mut ret = vec![];
         while ret.len() < n {
@@ -63,4 +71,3 @@ pub fn sample_nocopy<T: PartialEq + Clone>(from: &mut [T], n: usize) -> Vec<T> {
         ret
     }
 }
-
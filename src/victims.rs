@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+
+use super::metrics::Metric;
+use super::net::PeerRef;
+
+/// State of the view of each victim of a targeted attack
+#[derive(Default)]
+pub struct VictimStats {
+    victims: BTreeMap<PeerRef, Victim>,
+}
+
+#[derive(Clone)]
+struct Victim {
+    n_byzantine_neighbors: usize,
+    view_size: usize,
+    isolated_at: Option<u64>,
+}
+
+impl VictimStats {
+    pub fn new() -> Self {
+        Self{
+            victims: BTreeMap::new(),
+        }
+    }
+
+    /// `isolated_at` is the first time at which the view of the victim
+    /// contained only Byzantine nodes, if any
+    pub fn peer_new(peer: PeerRef, n_byzantine_neighbors: usize, view_size: usize, isolated_at: Option<u64>) -> Self {
+        let mut ret = Self::new();
+        ret.victims.insert(peer, Victim{ n_byzantine_neighbors, view_size, isolated_at });
+        ret
+    }
+
+    pub fn combine(&mut self, other: &Self) {
+        for (k, v) in other.victims.iter() {
+            self.victims.insert(*k, v.clone());
+        }
+    }
+
+    /// Fraction of Byzantine nodes in the view of each victim
+    pub fn byzantine_fractions(&self) -> Metric {
+        self.keyed(|v| Metric::ratio(v.n_byzantine_neighbors, v.view_size, 3))
+    }
+
+    /// Time at which each victim was isolated, if it was
+    pub fn isolation_times(&self) -> Metric {
+        self.keyed(|v| Metric::optional(v.isolated_at.map(|t| Metric::Int(t as i64))))
+    }
+
+    fn keyed<F: Fn(&Victim) -> Metric>(&self, f: F) -> Metric {
+        Metric::Keyed(self.victims.iter().map(|(peer, v)| (*peer as u64, f(v))).collect())
+    }
+}
+
+/// Records the first time at which a victim's view is made only of Byzantine nodes
+pub fn update_isolation(isolated_at: &mut Option<u64>, isolated: bool, time: u64) {
+    if isolated && isolated_at.is_none() {
+        *isolated_at = Some(time);
+    }
+}